@@ -23,8 +23,11 @@ pub async fn album(req: HttpRequest) -> Result<impl Responder> {
 
     album.tracks = Some(genius::get_album_tracks(album.id).await?);
 
-    Ok(template(AlbumTemplate {
-        settings: settings_from_req(&req),
-        album,
-    }))
+    Ok(template(
+        &req,
+        AlbumTemplate {
+            settings: settings_from_req(&req),
+            album,
+        },
+    ))
 }
@@ -1,79 +1,860 @@
-use std::io::{BufWriter, Cursor};
+use std::{io::Cursor, sync::LazyLock, time::Instant};
 
-use ::image::{EncodableLayout, ImageFormat, imageops::FilterType, load_from_memory};
-use actix_web::{HttpRequest, HttpResponse, Responder, get, http::StatusCode, http::header, web};
+use ::image::{
+    AnimationDecoder, DynamicImage, EncodableLayout, GenericImageView, ImageDecoder, ImageError,
+    ImageFormat, ImageReader, ImageResult, Limits,
+    codecs::{avif::AvifEncoder, gif::GifDecoder, jpeg::JpegEncoder},
+    error::{EncodingError, LimitError, LimitErrorKind},
+    guess_format,
+    imageops::FilterType,
+};
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, error, get, http::StatusCode, http::header, web,
+};
+use hmac::{Hmac, Mac};
+use log::debug;
 use serde::Deserialize;
+use sha2::Sha256;
+use urlencoding::encode;
 
 use crate::Result;
 use crate::genius::{self, SubDomain};
+use crate::image_cache;
+use crate::metrics;
+use crate::rate_limit::{self, RateLimiter};
+use crate::settings::{DataSaver, GifMode, Settings};
+
+/// The hosts images can be proxied from.
+const IMAGE_HOSTS: [&str; 2] = ["images.genius.com", "images.rapgenius.com"];
+/// The maximum width and height of images we'll decode.
+const MAX_IMAGE_DIMENSION: u32 = 10_000;
+/// The maximum amount of memory decoding a single image can allocate.
+const MAX_IMAGE_ALLOC: u64 = 256 * 1024 * 1024;
+/// The amount of bytes of the HMAC to include in signatures.
+const SIGNATURE_LEN: usize = 16;
+
+static RATE_LIMITER: LazyLock<Option<RateLimiter>> =
+    LazyLock::new(|| crate::args().image_rate_limit.map(RateLimiter::new));
+
+/// The sizes images are resized to. Requested sizes are snapped up to the nearest one.
+pub const SIZE_BUCKETS: [u32; 6] = [PLACEHOLDER_SIZE, 150, 300, 500, 750, 1000];
+/// The size of blurred placeholders shown to data saver users.
+pub const PLACEHOLDER_SIZE: u32 = 32;
+/// The maximum size of images shown to data saver users that want small images.
+pub const SMALL_SIZE: u32 = 150;
+
+/// The AVIF encoder's speed, from 1 (slowest, smallest) to 10 (fastest).
+const AVIF_SPEED: u8 = 8;
 
 #[derive(Debug, Deserialize)]
 pub struct UrlQuery {
     url: String,
+    /// The maximum width and height of the image.
     size: Option<u32>,
+    /// The maximum width of the image. Takes precedence over `size`.
+    w: Option<u32>,
+    /// The maximum height of the image. Takes precedence over `size`.
+    h: Option<u32>,
+    #[serde(default)]
+    fit: Fit,
+    /// The URL's signature, required if the instance signs image URLs.
+    sig: Option<String>,
+    /// Whether to only send the first frame of animated GIFs.
+    #[serde(default)]
+    still: bool,
+    /// Whether to convert animated GIFs to animated WebP, if the client supports it.
+    #[serde(default)]
+    webp: bool,
+}
+
+/// How an image is fit into the requested bounds. The aspect ratio is always preserved.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale the image down until it fits within the bounds.
+    #[default]
+    Contain,
+    /// Scale the image down until it covers the bounds, cropping the overflow from the center.
+    Cover,
+}
+
+/// The bounds to fit an image into. `None` leaves a dimension unbounded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Bounds {
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+}
+
+impl Bounds {
+    fn from_query(query: &UrlQuery) -> Self {
+        let positive = |v: Option<u32>| v.filter(|v| *v > 0);
+        Bounds {
+            width: positive(query.w).or(positive(query.size)).map(snap_size),
+            height: positive(query.h).or(positive(query.size)).map(snap_size),
+            fit: query.fit,
+        }
+    }
+
+    /// The variant of the image for the cache key, e.g. `500x500_cover`.
+    fn variant(&self) -> String {
+        let dimension = |v: Option<u32>| v.map(|v| v.to_string()).unwrap_or_default();
+        let fit = match self.fit {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        };
+        format!("{}x{}_{fit}", dimension(self.width), dimension(self.height))
+    }
 }
 
 #[get("/api/image")]
 pub async fn image(req: HttpRequest, info: web::Query<UrlQuery>) -> Result<impl Responder> {
-    let img_path = match info.url.split('/').next_back() {
+    if let Some(limiter) = &*RATE_LIMITER
+        && let Some(client) = rate_limit::client_ip(&req)
+        && let Err(wait) = limiter.check(client)
+    {
+        return Ok(HttpResponse::TooManyRequests()
+            .append_header((header::RETRY_AFTER, wait.as_secs().max(1)))
+            .finish());
+    }
+
+    let img_path = match image_path(&info.url) {
         Some(path) => path,
-        None => return Ok(HttpResponse::BadRequest().finish()),
+        None => {
+            return Ok(HttpResponse::from_error(error::ErrorBadRequest(
+                "Only images hosted by Genius can be proxied.",
+            )));
+        }
+    };
+
+    if let Some(key) = &crate::args().image_signing_key
+        && !info
+            .sig
+            .as_deref()
+            .is_some_and(|sig| verify(key, &info.url, sig))
+    {
+        return Ok(HttpResponse::from_error(error::ErrorForbidden(
+            "Invalid image signature.",
+        )));
+    }
+
+    let passthrough = crate::args().disable_image_transcoding;
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+    let format = OutputFormat::negotiate(accept);
+
+    let bounds = Bounds::from_query(&info);
+    let still = info.still && img_path.ends_with(".gif");
+    let animated_webp = info.webp
+        && !still
+        && img_path.ends_with(".gif")
+        && OutputFormat::WebP.quality(&parse_accept(accept)) > 0.0;
+    let cache = image_cache::get();
+    let cache_key = if passthrough {
+        image_cache::key(img_path, "original", "")
+    } else if animated_webp {
+        image_cache::key(
+            img_path,
+            &format!("{}_animated", bounds.variant()),
+            OutputFormat::WebP.extension(),
+        )
+    } else if still {
+        image_cache::key(
+            img_path,
+            &format!("{}_still", bounds.variant()),
+            format.extension(),
+        )
+    } else {
+        image_cache::key(img_path, &bounds.variant(), format.extension())
     };
+    if let Some(cache) = cache {
+        let cached = cache.get(cache_key.clone()).await;
+        metrics::observe_image_cache(cached.is_some());
+        if let Some(cached) = cached {
+            return send_image(cached.bytes, cached.content_type);
+        }
+    }
 
     let (status, body, headers) = genius::get_raw(SubDomain::Images, img_path, None).await?;
+    metrics::add_image_bytes_in(body.len());
 
     if status != StatusCode::OK {
         return Ok(HttpResponse::build(status).finish());
     }
 
-    // Directly pass through GIFs
-    if let Some(content_type) = headers.get("Content-Type")
-        && content_type.to_str()? == "image/gif"
+    let source_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ImageFormat::from_mime_type)
+        .or_else(|| guess_format(body.as_bytes()).ok())
+        .map(|format| format.to_mime_type());
+
+    // Convert animated GIFs to animated WebP, falling back to passing them through
+    if !passthrough && animated_webp && source_type == Some("image/gif") {
+        let start = Instant::now();
+        let quality = crate::args().image_quality;
+        let gif = body.clone();
+        match web::block(move || encode_animated_webp(gif.as_bytes(), bounds, quality)).await {
+            Ok(Ok(bytes)) => {
+                metrics::observe_transcode(start.elapsed());
+                if let Some(cache) = cache {
+                    cache.insert(cache_key, bytes.clone(), "image/webp").await;
+                }
+                return send_image(bytes, "image/webp");
+            }
+            Ok(Err(err)) => debug!("Failed to convert '{img_path}' to animated WebP: {err}"),
+            Err(_) => {}
+        }
+    }
+
+    // Directly pass through animated GIFs, and everything if transcoding is disabled
+    if let Some(content_type) = source_type
+        && (passthrough || (content_type == "image/gif" && !still))
     {
-        return send_image(body.as_bytes().to_vec(), "image/gif");
+        let bytes = body.as_bytes().to_vec();
+        if let Some(cache) = cache {
+            cache.insert(cache_key, bytes.clone(), content_type).await;
+        }
+        return send_image(bytes, content_type);
     }
 
-    let supports_webp = req
-        .headers()
-        .get(header::ACCEPT)
-        .map(|value| value.to_str().unwrap_or_default().contains("webp"))
-        .unwrap_or(false);
+    if !passthrough {
+        let start = Instant::now();
+        let quality = crate::args().image_quality;
+        // Decoding and encoding can take a while, so it's done off the worker's thread.
+        let transcoded = web::block(move || {
+            let img = decode(body.as_bytes())?;
+            format.encode(&resize(img, bounds), quality)
+        })
+        .await;
+        if let Ok(Ok(bytes)) = transcoded {
+            metrics::observe_transcode(start.elapsed());
+            if let Some(cache) = cache {
+                cache
+                    .insert(cache_key, bytes.clone(), format.content_type())
+                    .await;
+            }
+            return send_image(bytes, format.content_type());
+        }
+    }
 
-    if let Ok(abstract_image) = load_from_memory(body.as_bytes()) {
-        let size = info
-            .size
-            .unwrap_or(abstract_image.height())
-            .clamp(1, abstract_image.height().max(1000));
+    Ok(HttpResponse::InternalServerError().finish())
+}
 
+fn send_image(bytes: Vec<u8>, content_type: &'static str) -> Result<HttpResponse> {
+    metrics::add_image_bytes_out(bytes.len());
+    let mut response = HttpResponse::Ok();
+    response
+        .append_header(("Cache-Control", "public, max-age=31536000, immutable"))
+        .append_header(("Content-Type", content_type));
+    // The format depends on what the client accepts, so shared caches need to key on it.
+    if !crate::args().disable_image_transcoding {
+        response.append_header((header::VARY, "Accept"));
+    }
+    Ok(response.body(bytes))
+}
+
+/// A format proxied images can be transcoded to, in order of preference.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Avif,
+    WebP,
+    Jpeg,
+}
+
+impl OutputFormat {
+    const ALL: [OutputFormat; 3] = [OutputFormat::Avif, OutputFormat::WebP, OutputFormat::Jpeg];
+
+    /// Picks the format the client prefers from an `Accept` header, respecting q-values.
+    /// Ties are broken by our own preference, and JPEG is used if nothing is acceptable.
+    /// AVIF and WebP are only used if the client lists them, since many clients accepting
+    /// `image/*` or `*/*` can't decode them.
+    ///
+    /// `image/avif;q=0.5,image/webp,*/*;q=0.8` -> `WebP`
+    fn negotiate(accept: &str) -> Self {
+        let ranges = parse_accept(accept);
+        let mut best = (OutputFormat::Jpeg, 0.0);
+        for format in Self::ALL {
+            let q = format.quality(&ranges);
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    /// How much the client wants this format, from the media ranges of its `Accept` header.
+    fn quality(&self, ranges: &[(&str, f32)]) -> f32 {
+        let wildcards: &[&str] = match self {
+            Self::Jpeg => &["image/*", "*/*"],
+            _ => &[],
+        };
+        // The most specific range takes precedence.
+        [self.content_type()]
+            .iter()
+            .chain(wildcards)
+            .find_map(|media_type| {
+                ranges
+                    .iter()
+                    .find(|(range, _)| range.eq_ignore_ascii_case(media_type))
+                    .map(|(_, q)| *q)
+            })
+            .unwrap_or(0.0)
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Avif => "image/avif",
+            Self::WebP => "image/webp",
+            Self::Jpeg => "image/jpeg",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Avif => "avif",
+            Self::WebP => "webp",
+            Self::Jpeg => "jpg",
+        }
+    }
+
+    /// Encodes an image in this format.
+    /// The quality ranges from 1 to 100.
+    fn encode(&self, img: &DynamicImage, quality: u8) -> ImageResult<Vec<u8>> {
         // Images typically aren't smaller than 2kb
-        let mut buf = BufWriter::new(Cursor::new(Vec::with_capacity(2048)));
-        let resized = abstract_image.resize_exact(size, size, FilterType::Nearest);
+        let mut buf = Vec::with_capacity(2048);
+        match self {
+            Self::Avif => {
+                let img = if img.color().has_alpha() {
+                    DynamicImage::ImageRgba8(img.to_rgba8())
+                } else {
+                    DynamicImage::ImageRgb8(img.to_rgb8())
+                };
+                // Favor encoding speed, since AVIF is otherwise very slow to encode.
+                img.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                    &mut buf, AVIF_SPEED, quality,
+                ))?
+            }
+            Self::WebP => {
+                let img = DynamicImage::ImageRgba8(img.to_rgba8());
+                let encoded = webp::Encoder::from_rgba(img.as_bytes(), img.width(), img.height())
+                    .encode_simple(false, quality as f32)
+                    .map_err(|err| encoding_error(ImageFormat::WebP, format!("{err:?}")))?;
+                buf.extend_from_slice(&encoded);
+            }
+            Self::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut buf, quality))?,
+        }
+        Ok(buf)
+    }
+}
+
+/// Parses the media ranges of an `Accept` header with their q-values.
+fn parse_accept(accept: &str) -> Vec<(&str, f32)> {
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media_type = params.next()?.trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((media_type, quality.clamp(0.0, 1.0)))
+        })
+        .collect()
+}
 
-        let image_format = if supports_webp {
-            ImageFormat::WebP
+/// Creates an error for an image that couldn't be encoded.
+fn encoding_error(format: ImageFormat, err: String) -> ImageError {
+    ImageError::Encoding(EncodingError::new(format.into(), err))
+}
+
+/// Snaps a requested size up to the nearest size bucket, so only a bounded set of variants is made.
+fn snap_size(size: u32) -> u32 {
+    SIZE_BUCKETS
+        .into_iter()
+        .find(|bucket| *bucket >= size)
+        .unwrap_or(SIZE_BUCKETS[SIZE_BUCKETS.len() - 1])
+}
+
+/// A proxied image, for generating its `src` and `srcset` in templates.
+pub struct ProxiedImage {
+    url: String,
+    /// Whether the image is cropped to a square.
+    square: bool,
+    gif_mode: GifMode,
+    data_saver: Option<DataSaver>,
+}
+
+impl ProxiedImage {
+    /// An image scaled to fit within a square, preserving its aspect ratio.
+    pub fn new(url: &str, settings: &Settings) -> Self {
+        ProxiedImage {
+            url: image_url(url),
+            square: false,
+            gif_mode: settings.gif_mode(),
+            data_saver: settings.data_saver(),
+        }
+    }
+
+    /// Whether the image shouldn't be shown at all.
+    pub fn hidden(&self) -> bool {
+        self.data_saver == Some(DataSaver::Hide)
+    }
+
+    /// The extra CSS class of the image, if any.
+    pub fn class(&self) -> &'static str {
+        match self.data_saver {
+            Some(DataSaver::Placeholder) => "placeholder-image",
+            _ => "",
+        }
+    }
+
+    /// An image cropped to a square, e.g. cover art.
+    pub fn square(url: &str, settings: &Settings) -> Self {
+        ProxiedImage {
+            square: true,
+            ..Self::new(url, settings)
+        }
+    }
+
+    /// The URL of the image at a size, which is reduced for data saver users.
+    pub fn src(&self, size: u32) -> String {
+        let size = match self.data_saver {
+            Some(DataSaver::Placeholder) => PLACEHOLDER_SIZE,
+            Some(DataSaver::Small) => snap_size(size.min(SMALL_SIZE)),
+            _ => snap_size(size),
+        };
+        let mut src = if self.square {
+            format!("{}&w={size}&h={size}&fit=cover", self.url)
         } else {
-            ImageFormat::Jpeg
+            format!("{}&size={size}", self.url)
         };
+        match self.gif_mode {
+            GifMode::Still => src.push_str("&still=true"),
+            GifMode::WebP => src.push_str("&webp=true"),
+            GifMode::Animate => {}
+        }
+        src
+    }
 
-        if resized.write_to(&mut buf, image_format).is_ok() {
-            let bytes = buf.into_inner().unwrap().into_inner(); // Should never error
-            return send_image(
-                bytes,
-                if supports_webp {
-                    "image/webp"
-                } else {
-                    "image/jpeg"
-                },
-            );
+    /// The image at every size up to `max`, for the `srcset` attribute.
+    ///
+    /// `/api/image?url=...&size=150 150w, /api/image?url=...&size=300 300w`
+    /// Data saver users only get the image's `src`.
+    pub fn srcset(&self, max: u32) -> String {
+        if self.data_saver.is_some() {
+            return String::new();
         }
+        SIZE_BUCKETS
+            .into_iter()
+            .filter(|size| *size > PLACEHOLDER_SIZE && *size <= max)
+            .map(|size| format!("{} {size}w", self.src(size)))
+            .collect::<Vec<_>>()
+            .join(", ")
     }
+}
 
-    Ok(HttpResponse::InternalServerError().finish())
+/// Gets the URL to proxy an image through, signing it if the instance requires it.
+/// Parameters like the size can be appended to it.
+pub fn image_url(url: &str) -> String {
+    match sign(url) {
+        Some(sig) => format!("/api/image?url={}&sig={sig}", encode(url)),
+        None => format!("/api/image?url={}", encode(url)),
+    }
 }
 
-fn send_image(bytes: Vec<u8>, content_type: &'static str) -> Result<HttpResponse> {
-    Ok(HttpResponse::Ok()
-        .append_header(("Cache-Control", "public, max-age=31536000, immutable"))
-        .append_header(("Content-Type", content_type))
-        .body(bytes))
+/// Gets the path of an image on Genius' image host.
+/// Will return `None` if the image isn't hosted by Genius.
+///
+/// `https://images.genius.com/abc.1000x1000x1.png` -> `abc.1000x1000x1.png`
+fn image_path(url: &str) -> Option<&str> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+    let (host, path) = rest.split_once('/')?;
+    if path.contains(['?', '#']) {
+        return None;
+    }
+    let path = path.rsplit('/').next()?;
+    (IMAGE_HOSTS.contains(&host)
+        && !path.is_empty()
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_'))
+    .then_some(path)
+}
+
+fn mac(key: &str, url: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(url.as_bytes());
+    mac
+}
+
+/// Signs an image URL with the instance's key, if it has one.
+fn sign(url: &str) -> Option<String> {
+    let key = crate::args().image_signing_key.as_ref()?;
+    Some(signature(key, url))
+}
+
+fn signature(key: &str, url: &str) -> String {
+    mac(key, url).finalize().into_bytes()[..SIGNATURE_LEN]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn verify(key: &str, url: &str, sig: &str) -> bool {
+    let bytes: Option<Vec<u8>> = (0..sig.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(sig.get(i..i + 2)?, 16).ok())
+        .collect();
+    bytes.is_some_and(|bytes| {
+        bytes.len() == SIGNATURE_LEN && mac(key, url).verify_truncated_left(&bytes).is_ok()
+    })
+}
+
+/// Decodes an image, refusing any that are too large to safely process.
+fn decode(bytes: &[u8]) -> ImageResult<DynamicImage> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits());
+    reader.decode()
+}
+
+/// The limits for decoding images.
+fn limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_IMAGE_ALLOC);
+    limits
+}
+
+/// Converts an animated GIF to an animated WebP, resizing every frame to fit the bounds.
+/// Fails if the decoded frames would take up too much memory.
+fn encode_animated_webp(bytes: &[u8], bounds: Bounds, quality: u8) -> ImageResult<Vec<u8>> {
+    let mut decoder = GifDecoder::new(Cursor::new(bytes))?;
+    decoder.set_limits(limits())?;
+
+    let mut frames = Vec::new();
+    let mut size = 0;
+    let mut timestamp = 0;
+    for frame in decoder.into_frames() {
+        let frame = frame?;
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        let delay = numerator / denominator.max(1);
+        let img = resize(DynamicImage::ImageRgba8(frame.into_buffer()), bounds).to_rgba8();
+        size += img.as_raw().len() as u64;
+        if size > MAX_IMAGE_ALLOC {
+            return Err(ImageError::Limits(LimitError::from_kind(
+                LimitErrorKind::InsufficientMemory,
+            )));
+        }
+        frames.push((img, timestamp));
+        // Browsers show frames with tiny delays for 100ms, so do the same.
+        timestamp += if delay <= 10 { 100 } else { delay as i32 };
+    }
+    let Some((width, height)) = frames.first().map(|(img, _)| img.dimensions()) else {
+        return Err(encoding_error(ImageFormat::Gif, "No frames".to_owned()));
+    };
+
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| encoding_error(ImageFormat::WebP, "Invalid config".to_owned()))?;
+    config.quality = quality as f32;
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    for (img, timestamp) in &frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(img, width, height, *timestamp));
+    }
+    let encoded = encoder
+        .try_encode()
+        .map_err(|err| encoding_error(ImageFormat::WebP, format!("{err:?}")))?;
+    Ok(encoded.to_vec())
+}
+
+/// Resizes an image to fit the bounds while preserving its aspect ratio.
+/// Images are only ever scaled down, never up.
+fn resize(img: DynamicImage, bounds: Bounds) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return img;
+    }
+    let max_width = bounds.width.unwrap_or(width).min(width);
+    let max_height = bounds.height.unwrap_or(height).min(height);
+
+    let scale_x = max_width as f64 / width as f64;
+    let scale_y = max_height as f64 / height as f64;
+    let scale = match bounds.fit {
+        Fit::Contain => scale_x.min(scale_y),
+        Fit::Cover => scale_x.max(scale_y),
+    };
+    let scaled_width = ((width as f64 * scale).round() as u32).clamp(1, width);
+    let scaled_height = ((height as f64 * scale).round() as u32).clamp(1, height);
+
+    let scaled = if (scaled_width, scaled_height) == (width, height) {
+        img
+    } else {
+        img.resize_exact(scaled_width, scaled_height, FilterType::CatmullRom)
+    };
+
+    match bounds.fit {
+        Fit::Contain => scaled,
+        Fit::Cover => {
+            let crop_width = max_width.min(scaled_width);
+            let crop_height = max_height.min(scaled_height);
+            if (crop_width, crop_height) == (scaled_width, scaled_height) {
+                scaled
+            } else {
+                scaled.crop_imm(
+                    (scaled_width - crop_width) / 2,
+                    (scaled_height - crop_height) / 2,
+                    crop_width,
+                    crop_height,
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::image::{Rgb, RgbImage};
+
+    use super::*;
+
+    /// A fixture image with a red left half and a blue right half.
+    fn fixture(width: u32, height: u32) -> DynamicImage {
+        RgbImage::from_fn(width, height, |x, _| {
+            if x < width / 2 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        })
+        .into()
+    }
+
+    fn bounds(width: Option<u32>, height: Option<u32>, fit: Fit) -> Bounds {
+        Bounds { width, height, fit }
+    }
+
+    #[test]
+    fn contain_preserves_aspect_ratio() {
+        let resized = resize(
+            fixture(1000, 500),
+            bounds(Some(300), Some(300), Fit::Contain),
+        );
+        assert_eq!(resized.dimensions(), (300, 150));
+
+        let resized = resize(fixture(400, 1000), bounds(None, Some(500), Fit::Contain));
+        assert_eq!(resized.dimensions(), (200, 500));
+    }
+
+    #[test]
+    fn cover_crops_from_center() {
+        let resized = resize(fixture(1000, 500), bounds(Some(300), Some(300), Fit::Cover));
+        assert_eq!(resized.dimensions(), (300, 300));
+        // The split between both halves should stay in the middle.
+        let resized = resized.to_rgb8();
+        assert_eq!(resized.get_pixel(140, 150), &Rgb([255, 0, 0]));
+        assert_eq!(resized.get_pixel(160, 150), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn never_upscales() {
+        let resized = resize(
+            fixture(200, 100),
+            bounds(Some(500), Some(500), Fit::Contain),
+        );
+        assert_eq!(resized.dimensions(), (200, 100));
+
+        let resized = resize(fixture(200, 100), bounds(Some(500), Some(500), Fit::Cover));
+        assert_eq!(resized.dimensions(), (200, 100));
+
+        let resized = resize(fixture(200, 100), bounds(Some(150), Some(150), Fit::Cover));
+        assert_eq!(resized.dimensions(), (150, 100));
+    }
+
+    #[test]
+    fn negotiate_format() {
+        let negotiate = OutputFormat::negotiate;
+        assert_eq!(
+            negotiate("image/avif,image/webp,image/apng,image/*,*/*;q=0.8"),
+            OutputFormat::Avif
+        );
+        assert_eq!(
+            negotiate("image/avif;q=0.5,image/webp,*/*;q=0.8"),
+            OutputFormat::WebP
+        );
+        assert_eq!(
+            negotiate("image/webp;q=0.5, image/*;q=0.8"),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(negotiate("image/webp, image/*"), OutputFormat::WebP);
+        assert_eq!(
+            negotiate("image/png,image/*;q=0.8,*/*;q=0.5"),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(negotiate("*/*"), OutputFormat::Jpeg);
+        assert_eq!(
+            negotiate("image/jpeg, image/webp;q=0.9"),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(negotiate("text/html"), OutputFormat::Jpeg);
+        assert_eq!(negotiate(""), OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn encode_formats() {
+        let img = fixture(64, 32);
+        for format in OutputFormat::ALL {
+            let bytes = format.encode(&img, 80).unwrap();
+            assert_eq!(
+                guess_format(&bytes).unwrap().to_mime_type(),
+                format.content_type()
+            );
+        }
+    }
+
+    #[test]
+    fn only_genius_images() {
+        assert_eq!(
+            image_path("https://images.genius.com/abc.1000x1000x1.png"),
+            Some("abc.1000x1000x1.png")
+        );
+        assert_eq!(
+            image_path("https://images.rapgenius.com/avatars/abc.jpg"),
+            Some("abc.jpg")
+        );
+        assert_eq!(image_path("https://example.com/abc.png"), None);
+        assert_eq!(
+            image_path("https://images.genius.com.example.com/abc.png"),
+            None
+        );
+        assert_eq!(image_path("https://images.genius.com/"), None);
+        assert_eq!(image_path("https://images.genius.com/a.png?x=../b"), None);
+        assert_eq!(image_path("images.genius.com/abc.png"), None);
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let url = "https://images.genius.com/abc.png";
+        let sig = signature("key", url);
+        assert_eq!(sig.len(), SIGNATURE_LEN * 2);
+        assert!(verify("key", url, &sig));
+        assert!(!verify("other", url, &sig));
+        assert!(!verify("key", "https://images.genius.com/def.png", &sig));
+        assert!(!verify("key", url, &sig[..8]));
+        assert!(!verify("key", url, "not hex"));
+    }
+
+    #[test]
+    fn decode_limits_dimensions() {
+        let mut bytes = Vec::new();
+        fixture(MAX_IMAGE_DIMENSION + 1, 1)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        assert!(decode(&bytes).is_err());
+
+        bytes.clear();
+        fixture(100, 1)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        assert!(decode(&bytes).is_ok());
+    }
+
+    #[test]
+    fn decode_first_gif_frame() {
+        let mut bytes = Vec::new();
+        let mut encoder = ::image::codecs::gif::GifEncoder::new(&mut bytes);
+        for color in [[255, 0, 0, 255], [0, 0, 255, 255]] {
+            let frame = ::image::RgbaImage::from_pixel(4, 4, ::image::Rgba(color));
+            encoder.encode_frame(::image::Frame::new(frame)).unwrap();
+        }
+        drop(encoder);
+
+        let still = decode(&bytes).unwrap().to_rgba8();
+        assert_eq!(still.get_pixel(0, 0), &::image::Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn convert_gif_to_animated_webp() {
+        let mut bytes = Vec::new();
+        let mut encoder = ::image::codecs::gif::GifEncoder::new(&mut bytes);
+        for color in [[255, 0, 0, 255], [0, 0, 255, 255]] {
+            let frame = ::image::RgbaImage::from_pixel(40, 20, ::image::Rgba(color));
+            let delay = ::image::Delay::from_numer_denom_ms(200, 1);
+            encoder
+                .encode_frame(::image::Frame::from_parts(frame, 0, 0, delay))
+                .unwrap();
+        }
+        drop(encoder);
+
+        let bounds = bounds(Some(10), Some(10), Fit::Contain);
+        let webp = encode_animated_webp(&bytes, bounds, 80).unwrap();
+        let decoder = ::image::codecs::webp::WebPDecoder::new(Cursor::new(webp)).unwrap();
+        let frames = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(frames.len(), 2);
+        for frame in &frames {
+            assert_eq!(frame.buffer().dimensions(), (10, 5));
+            assert_eq!(frame.delay().numer_denom_ms(), (200, 1));
+        }
+    }
+
+    #[test]
+    fn srcset_uses_size_buckets() {
+        let cover = ProxiedImage::square("https://images.genius.com/a.png", &Settings::default());
+        assert_eq!(
+            cover.src(400),
+            "/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.png&w=500&h=500&fit=cover"
+        );
+        let srcset = cover.srcset(300);
+        assert_eq!(srcset.matches(", ").count(), 1);
+        assert!(srcset.ends_with("&w=300&h=300&fit=cover 300w"));
+    }
+
+    #[test]
+    fn data_saver_reduces_sizes() {
+        let settings = |data_saver: &str| Settings {
+            data_saver: data_saver.to_owned(),
+            ..Settings::default()
+        };
+        let url = "https://images.genius.com/a.png";
+
+        let small = ProxiedImage::new(url, &settings("small"));
+        assert!(small.src(500).ends_with("&size=150"));
+        assert_eq!(small.srcset(1000), "");
+
+        let placeholder = ProxiedImage::new(url, &settings("placeholder"));
+        assert!(placeholder.src(500).ends_with("&size=32"));
+        assert_eq!(placeholder.class(), "placeholder-image");
+
+        assert!(ProxiedImage::new(url, &settings("hide")).hidden());
+    }
+
+    #[test]
+    fn query_bounds() {
+        let query = |s: &str| web::Query::<UrlQuery>::from_query(s).unwrap().into_inner();
+        assert_eq!(
+            Bounds::from_query(&query("url=a&size=500")),
+            bounds(Some(500), Some(500), Fit::Contain)
+        );
+        assert_eq!(
+            Bounds::from_query(&query("url=a&size=500&w=300&fit=cover")),
+            bounds(Some(300), Some(500), Fit::Cover)
+        );
+        assert_eq!(
+            Bounds::from_query(&query("url=a&w=0")).variant(),
+            "x_contain"
+        );
+        assert_eq!(
+            Bounds::from_query(&query("url=a&w=320&h=5000")),
+            bounds(Some(500), Some(1000), Fit::Contain)
+        );
+        assert!(web::Query::<UrlQuery>::from_query("url=a&fit=stretch").is_err());
+    }
 }
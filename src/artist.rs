@@ -1,3 +1,4 @@
+use crate::frontends::Frontends;
 use crate::settings::{Settings, settings_from_req};
 use crate::utils;
 use actix_web::{HttpRequest, Responder, Result, get};
@@ -5,12 +6,14 @@ use askama::Template;
 
 use crate::genius::{self, GeniusArtist};
 use crate::genius::{GeniusArtistResponse, SortMode};
+use crate::sanitize;
 use crate::templates::template;
 
 #[derive(Template)]
 #[template(path = "artist.html")]
 struct ArtistTemplate {
     settings: Settings,
+    frontends: Frontends,
     artist: GeniusArtist,
 }
 
@@ -25,8 +28,19 @@ pub async fn artist(req: HttpRequest) -> Result<impl Responder> {
     artist.popular_songs =
         Some(genius::get_artist_songs(artist.id, SortMode::Popularity, MAX_SONGS).await?);
 
-    Ok(template(ArtistTemplate {
-        settings: settings_from_req(&req),
-        artist,
-    }))
+    let settings = settings_from_req(&req);
+    let frontends = Frontends::for_settings(&settings);
+
+    if let Some(description) = artist.description.as_mut() {
+        description.html = sanitize::personalize(&description.html, &settings, &frontends);
+    }
+
+    Ok(template(
+        &req,
+        ArtistTemplate {
+            settings,
+            frontends,
+            artist,
+        },
+    ))
 }
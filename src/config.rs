@@ -0,0 +1,237 @@
+use std::{env, ffi::OsString, fs, path::Path};
+
+use clap::{ArgMatches, CommandFactory, FromArgMatches, error::ErrorKind};
+use serde::Serializer;
+use toml::{Table, Value};
+
+use crate::{Args, resource, tls};
+
+/// Options that can't be set in the config file.
+const CLI_ONLY: [&str; 3] = ["config", "help", "version"];
+
+/// Parses the instance's configuration, exiting if it's invalid.
+pub fn parse() -> Args {
+    try_parse_from(env::args_os()).unwrap_or_else(|err| err.exit())
+}
+
+/// Parses the instance's configuration from the given arguments.
+///
+/// Options are taken from, in order of precedence, the command line, `INTELLECTUAL_*` env vars,
+/// the config file, and their defaults.
+pub fn try_parse_from<I, T>(args: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let mut command = Args::command();
+
+    // The config file provides the defaults for the other options, so it has to be found first.
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .get_matches_from(args.clone());
+    if let Some(path) = matches.get_one::<String>("config") {
+        for (key, value) in read_config(Path::new(path))? {
+            let values = to_arg_values(&key, value)?;
+            if CLI_ONLY.contains(&key.as_str())
+                || !command
+                    .get_arguments()
+                    .any(|arg| arg.get_id() == key.as_str())
+            {
+                return Err(command.error(
+                    ErrorKind::UnknownArgument,
+                    format!("Unknown option '{key}' in config file '{path}'"),
+                ));
+            }
+            command = command.mut_arg(key, |arg| arg.default_values(values));
+        }
+    }
+
+    // PORT is commonly set by hosting platforms, so it's still supported.
+    if env::var_os("INTELLECTUAL_PORT").is_none()
+        && let Ok(port) = env::var("PORT")
+    {
+        command = command.mut_arg("port", |arg| arg.default_value(port));
+    }
+
+    let matches: ArgMatches = command.try_get_matches_from(args)?;
+    Args::from_arg_matches(&matches)
+}
+
+fn read_config(path: &Path) -> Result<Table, clap::Error> {
+    let error = |message: String| Args::command().error(ErrorKind::Io, message);
+    let contents = fs::read_to_string(path).map_err(|err| {
+        error(format!(
+            "Failed to read config file '{}': {err}",
+            path.display()
+        ))
+    })?;
+    contents.parse().map_err(|err| {
+        error(format!(
+            "Failed to parse config file '{}': {err}",
+            path.display()
+        ))
+    })
+}
+
+/// Converts a value from the config file to the values it'd have on the command line.
+fn to_arg_values(key: &str, value: Value) -> Result<Vec<String>, clap::Error> {
+    match value {
+        Value::String(value) => Ok(vec![value]),
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| to_arg_value(key, value))
+            .collect(),
+        value => to_arg_value(key, value).map(|value| vec![value]),
+    }
+}
+
+/// Converts a single value from the config file to the value it'd have on the command line.
+fn to_arg_value(key: &str, value: Value) -> Result<String, clap::Error> {
+    match value {
+        Value::String(value) => Ok(value),
+        Value::Array(_) | Value::Table(_) | Value::Datetime(_) => Err(Args::command().error(
+            ErrorKind::InvalidValue,
+            format!("Invalid value for '{key}' in config file"),
+        )),
+        value => Ok(value.to_string()),
+    }
+}
+
+/// Validates the configuration beyond what can be checked while parsing it,
+/// and prints the effective configuration.
+/// Returns the exit code.
+pub fn check(args: &Args) -> i32 {
+    let mut errors = Vec::new();
+    if args.tls
+        && let (Some(cert), Some(key)) = (&args.tls_cert_file, &args.tls_key_file)
+        && let Err(err) = tls::load(cert, key)
+    {
+        errors.push(err);
+    }
+    if let Err(err) = resource::init() {
+        errors.push(format!("Failed to load static overrides: {err}"));
+    }
+    if let Some(dir) = &args.image_cache_dir
+        && let Err(err) = check_cache_dir(Path::new(dir))
+    {
+        errors.push(format!("Invalid image cache '{dir}': {err}"));
+    }
+
+    if !errors.is_empty() {
+        for error in errors {
+            eprintln!("error: {error}");
+        }
+        return 1;
+    }
+    match toml::to_string(args) {
+        Ok(config) => {
+            print!("{config}");
+            0
+        }
+        Err(err) => {
+            eprintln!("error: Failed to print configuration: {err}");
+            1
+        }
+    }
+}
+
+/// Checks that the image cache can be written to, without creating it.
+/// It's created on startup if it doesn't exist, so then its closest existing parent needs to be writable.
+fn check_cache_dir(dir: &Path) -> Result<(), String> {
+    let existing = dir
+        .ancestors()
+        .map(|path| {
+            if path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                path
+            }
+        })
+        .find(|path| path.exists())
+        .unwrap_or(Path::new("."));
+    if !existing.is_dir() {
+        return Err(format!("'{}' isn't a directory", existing.display()));
+    }
+    if !is_writable(existing) {
+        return Err(format!("'{}' isn't writable", existing.display()));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_writable(dir: &Path) -> bool {
+    use rustix::fs::{Access, access};
+
+    access(dir, Access::WRITE_OK | Access::EXEC_OK).is_ok()
+}
+
+#[cfg(not(unix))]
+fn is_writable(dir: &Path) -> bool {
+    fs::metadata(dir).is_ok_and(|metadata| !metadata.permissions().readonly())
+}
+
+/// Prints permissions in octal, the way they're configured.
+pub fn octal<S: Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{mode:o}"))
+}
+
+/// Hides a secret when printing the configuration.
+pub fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let path = env::temp_dir().join(format!("intellectual-test-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "port = 9000\nworkers = 2\ntrust_proxy_headers = true\nfrontend = [\"youtube=https://yewtu.be\"]\n",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+
+        let args = try_parse_from(["intellectual", "--config", config, "--port", "9001"]).unwrap();
+        assert_eq!(args.port, 9001);
+        assert_eq!(args.workers, 2);
+        assert!(args.trust_proxy_headers);
+        assert_eq!(args.frontend[0].url, "https://yewtu.be");
+
+        fs::write(&path, "prot = 9000\n").unwrap();
+        assert!(try_parse_from(["intellectual", "--config", config]).is_err());
+        fs::write(&path, "port = \"not a port\"\n").unwrap();
+        assert!(try_parse_from(["intellectual", "--config", config]).is_err());
+        fs::write(&path, "frontend = [[]]\n").unwrap();
+        assert!(try_parse_from(["intellectual", "--config", config]).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn check_doesnt_create_cache_dir() {
+        let dir = env::temp_dir().join(format!("intellectual-check-{}", std::process::id()));
+        assert!(check_cache_dir(&dir.join("images")).is_ok());
+        assert!(!dir.exists());
+
+        let file = env::temp_dir().join(format!("intellectual-check-{}.txt", std::process::id()));
+        fs::write(&file, "").unwrap();
+        assert!(check_cache_dir(&file.join("images")).is_err());
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut args = try_parse_from(["intellectual"]).unwrap();
+        args.image_signing_key = Some("hunter2".into());
+        let config = toml::to_string(&args).unwrap();
+        assert!(config.contains("image_signing_key = \"<redacted>\""));
+        assert!(!config.contains("hunter2"));
+    }
+}
@@ -1,32 +1,33 @@
 use actix_web::{
-    self, HttpResponse, Result,
-    dev::ServiceResponse,
-    http::header::{self},
-    middleware::ErrorHandlerResponse,
+    self, HttpMessage, HttpResponse, Result, dev::ServiceResponse, middleware::ErrorHandlerResponse,
 };
 use askama::Template;
-use awc::error::HeaderValue;
 use log::error;
 
 use crate::{
+    logging::{self, RequestId},
     settings::{Settings, settings_from_req},
     templates::template_with_res,
 };
 
 pub fn render_500<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
     let err = get_err_str(&res);
+    let request_id = get_request_id(&res);
     if let Some(str) = &err {
         error!(
+            request_id = request_id.as_deref();
             "Error processing request for '{}': {str}",
-            res.request().uri()
+            logging::path(res.request())
         );
     }
 
     let new_response = template_with_res(
+        res.request(),
         HttpResponse::InternalServerError(),
         InternalErrorTemplate {
             settings: settings_from_req(res.request()),
             err,
+            request_id,
         },
     );
     create(res, new_response)
@@ -34,6 +35,7 @@ pub fn render_500<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>>
 
 pub fn render_404<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
     let new_response = template_with_res(
+        res.request(),
         HttpResponse::NotFound(),
         NotFoundTemplate {
             settings: settings_from_req(res.request()),
@@ -44,10 +46,12 @@ pub fn render_404<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>>
 
 pub fn render_400<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
     let new_response = template_with_res(
+        res.request(),
         HttpResponse::BadRequest(),
         BadRequestTemplate {
             settings: settings_from_req(res.request()),
             err: get_err_str(&res),
+            request_id: get_request_id(&res),
         },
     );
     create(res, new_response)
@@ -55,11 +59,8 @@ pub fn render_400<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>>
 
 fn create<B>(
     res: ServiceResponse<B>,
-    mut new_response: HttpResponse,
+    new_response: HttpResponse,
 ) -> Result<ErrorHandlerResponse<B>> {
-    new_response
-        .headers_mut()
-        .append(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
     Ok(ErrorHandlerResponse::Response(
         ServiceResponse::new(res.into_parts().0, new_response).map_into_right_body(),
     ))
@@ -69,11 +70,19 @@ fn get_err_str<B>(res: &ServiceResponse<B>) -> Option<String> {
     res.response().error().map(|err| err.to_string())
 }
 
+fn get_request_id<B>(res: &ServiceResponse<B>) -> Option<String> {
+    res.request()
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+}
+
 #[derive(Template)]
 #[template(path = "500.html")]
 struct InternalErrorTemplate {
     settings: Settings,
     err: Option<String>,
+    request_id: Option<String>,
 }
 
 #[derive(Template)]
@@ -87,4 +96,5 @@ struct NotFoundTemplate {
 struct BadRequestTemplate {
     settings: Settings,
     err: Option<String>,
+    request_id: Option<String>,
 }
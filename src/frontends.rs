@@ -0,0 +1,298 @@
+use std::{str::FromStr, sync::LazyLock};
+
+use lazy_regex::*;
+use regex::{Captures, Regex};
+use serde::{Serialize, Serializer};
+
+use crate::settings::Settings;
+
+static HREF_PATTERN: Lazy<Regex> = lazy_regex!(r#"href="(https?://[^"]*)""#);
+static INSTANCE_FRONTENDS: LazyLock<Frontends> =
+    LazyLock::new(|| Frontends(crate::args().frontend.clone()));
+
+/// A service that can be redirected to a privacy-respecting frontend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Service {
+    YouTube,
+    Twitter,
+    Reddit,
+    Instagram,
+    TikTok,
+    Wikipedia,
+}
+
+impl Service {
+    pub const ALL: [Service; 6] = [
+        Service::YouTube,
+        Service::Twitter,
+        Service::Reddit,
+        Service::Instagram,
+        Service::TikTok,
+        Service::Wikipedia,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::YouTube => "youtube",
+            Self::Twitter => "twitter",
+            Self::Reddit => "reddit",
+            Self::Instagram => "instagram",
+            Self::TikTok => "tiktok",
+            Self::Wikipedia => "wikipedia",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::YouTube => "YouTube",
+            Self::Twitter => "Twitter/X",
+            Self::Reddit => "Reddit",
+            Self::Instagram => "Instagram",
+            Self::TikTok => "TikTok",
+            Self::Wikipedia => "Wikipedia",
+        }
+    }
+
+    /// Examples of frontends for the service, shown in the settings.
+    pub fn examples(&self) -> &'static str {
+        match self {
+            Self::YouTube => "Invidious, Piped",
+            Self::Twitter => "Nitter",
+            Self::Reddit => "Redlib",
+            Self::Instagram => "Proxigram",
+            Self::TikTok => "ProxiTok",
+            Self::Wikipedia => "Wikiless",
+        }
+    }
+
+    /// The hosts this service's links use.
+    /// Hosts starting with a `.` match any subdomain.
+    fn hosts(&self) -> &'static [&'static str] {
+        match self {
+            Self::YouTube => &[
+                "youtube.com",
+                "www.youtube.com",
+                "m.youtube.com",
+                "music.youtube.com",
+                "youtu.be",
+                "youtube-nocookie.com",
+                "www.youtube-nocookie.com",
+            ],
+            Self::Twitter => &[
+                "twitter.com",
+                "www.twitter.com",
+                "mobile.twitter.com",
+                "x.com",
+                "www.x.com",
+            ],
+            Self::Reddit => &["reddit.com", "www.reddit.com", "old.reddit.com"],
+            Self::Instagram => &["instagram.com", "www.instagram.com"],
+            Self::TikTok => &["tiktok.com", "www.tiktok.com"],
+            Self::Wikipedia => &[".wikipedia.org"],
+        }
+    }
+
+    fn from_host(host: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|service| {
+            service.hosts().iter().any(|h| match h.strip_prefix('.') {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => *h == host,
+            })
+        })
+    }
+}
+
+/// A frontend to redirect links for a service to, e.g. `youtube=https://yewtu.be`.
+#[derive(Clone, Debug)]
+pub struct Frontend {
+    pub service: Service,
+    pub url: String,
+}
+
+impl Serialize for Frontend {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{}={}", self.service.id(), self.url))
+    }
+}
+
+impl FromStr for Frontend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, url) = s
+            .split_once('=')
+            .ok_or("Expected a frontend in the format '<service>=<url>'")?;
+        let service = Service::ALL
+            .into_iter()
+            .find(|service| service.id() == id)
+            .ok_or_else(|| format!("Unknown service '{id}'"))?;
+        if !is_valid_url(url) {
+            return Err(format!("Invalid frontend URL '{url}'"));
+        }
+        Ok(Frontend {
+            service,
+            url: url.trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+/// Whether a user-supplied frontend URL is an absolute HTTP(S) URL that's safe to put in an attribute.
+pub fn is_valid_url(url: &str) -> bool {
+    let rest = match url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+    !rest.is_empty()
+        && rest
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~:/?#[]@!$&()*+,;=%".contains(c))
+}
+
+/// The frontends to redirect links to.
+pub struct Frontends(Vec<Frontend>);
+
+impl Frontends {
+    /// The frontends configured for the instance.
+    pub fn instance() -> &'static Self {
+        &INSTANCE_FRONTENDS
+    }
+
+    /// The frontends to use for a user, with their own choices taking precedence over the instance's.
+    pub fn for_settings(settings: &Settings) -> Self {
+        let mut frontends = Frontends(Self::instance().0.clone());
+        for service in Service::ALL {
+            let url = settings.frontend(&service);
+            if !url.is_empty() {
+                frontends.0.retain(|f| f.service != service);
+                frontends.0.push(Frontend {
+                    service,
+                    url: url.trim_end_matches('/').to_owned(),
+                });
+            }
+        }
+        frontends
+    }
+
+    pub fn get(&self, service: Service) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|f| f.service == service)
+            .map(|f| f.url.as_str())
+    }
+
+    /// Redirects a link to its service's frontend.
+    /// Will return the link unchanged if its service doesn't have a frontend.
+    ///
+    /// `https://www.youtube.com/watch?v=id` -> `https://yewtu.be/watch?v=id`
+    pub fn redirect(&self, url: &str) -> String {
+        self.try_redirect(url).unwrap_or_else(|| url.to_owned())
+    }
+
+    fn try_redirect(&self, url: &str) -> Option<String> {
+        let (host, path) = split_url(url)?;
+        let service = Service::from_host(host)?;
+        let frontend = self.get(service)?;
+        Some(match (service, host) {
+            // Not all frontends support short links.
+            (Service::YouTube, "youtu.be") => {
+                let path = path.trim_start_matches('/');
+                match path.split_once('?') {
+                    Some((id, query)) => format!("{frontend}/watch?v={id}&{query}"),
+                    None => format!("{frontend}/watch?v={path}"),
+                }
+            }
+            // Keep the article's language, which is otherwise only in the subdomain.
+            (Service::Wikipedia, host) => match host.split('.').next().unwrap_or_default() {
+                "en" | "www" | "m" => format!("{frontend}{path}"),
+                lang if path.contains('?') => format!("{frontend}{path}&lang={lang}"),
+                lang => format!("{frontend}{path}?lang={lang}"),
+            },
+            _ => format!("{frontend}{path}"),
+        })
+    }
+
+    /// Redirects all links in an HTML document to their service's frontend.
+    /// Links that were already redirected to the instance's frontends are moved to these frontends.
+    pub fn rewrite_html(&self, html: &str) -> String {
+        let instance = Self::instance();
+        HREF_PATTERN
+            .replace_all(html, |caps: &Captures| {
+                let url = &caps[1];
+                let redirected = instance
+                    .0
+                    .iter()
+                    .find_map(|f| {
+                        let path = url
+                            .strip_prefix(&f.url)
+                            .filter(|path| path.is_empty() || path.starts_with(['/', '?', '#']))?;
+                        Some(format!("{}{path}", self.get(f.service)?))
+                    })
+                    .unwrap_or_else(|| self.redirect(url));
+                format!(r#"href="{redirected}""#)
+            })
+            .into_owned()
+    }
+}
+
+/// Gets the origin of an absolute URL, e.g. `https://yewtu.be`.
+pub fn origin(url: &str) -> Option<&str> {
+    split_url(url).map(|(_, rest)| &url[..url.len() - rest.len()])
+}
+
+/// Splits an absolute URL into its host and the rest, e.g. `("youtu.be", "/id")`.
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+    Some(rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frontends() -> Frontends {
+        Frontends(vec![
+            "youtube=https://yewtu.be".parse().unwrap(),
+            "wikipedia=https://wikiless.org/".parse().unwrap(),
+        ])
+    }
+
+    #[test]
+    fn redirect_known_services() {
+        let frontends = frontends();
+        assert_eq!(
+            frontends.redirect("https://www.youtube.com/watch?v=id"),
+            "https://yewtu.be/watch?v=id"
+        );
+        assert_eq!(
+            frontends.redirect("https://youtu.be/id?t=5"),
+            "https://yewtu.be/watch?v=id&t=5"
+        );
+        assert_eq!(
+            frontends.redirect("https://de.wikipedia.org/wiki/Rap"),
+            "https://wikiless.org/wiki/Rap?lang=de"
+        );
+        assert_eq!(
+            frontends.redirect("https://twitter.com/someone"),
+            "https://twitter.com/someone"
+        );
+        assert_eq!(
+            frontends.redirect("https://notyoutube.com/watch"),
+            "https://notyoutube.com/watch"
+        );
+    }
+
+    #[test]
+    fn rewrite_html_links() {
+        assert_eq!(
+            frontends().rewrite_html(r#"<a href="https://m.youtube.com/watch?v=id">Video</a>"#),
+            r#"<a href="https://yewtu.be/watch?v=id">Video</a>"#
+        );
+    }
+}
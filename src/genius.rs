@@ -1,15 +1,19 @@
-use std::{sync::LazyLock, time::Duration};
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
 
 use crate::Result;
+use crate::logging;
+use crate::metrics;
+use crate::sanitize;
 use actix_web::{
     dev::{Decompress, Payload},
     http::{StatusCode, header::HeaderMap},
     web::Bytes,
 };
 use awc::{Client, ClientResponse};
-use lazy_regex::*;
 use log::debug;
-use regex::Regex;
 use scraper::{Html, Selector};
 use serde::{Deserialize, Deserializer, de::DeserializeOwned};
 use urlencoding::encode;
@@ -85,6 +89,22 @@ pub async fn get_search_results(query: &str, page: u8) -> Result<Vec<GeniusSong>
     .collect())
 }
 
+/// Searches for songs by their lyrics.
+pub async fn get_lyric_search_results(query: &str, page: u8) -> Result<Vec<GeniusSong>> {
+    Ok(get_json::<GeniusSectionSearchRequest>(
+        SubDomain::Api,
+        "search/lyric",
+        Some(vec![("q", query), ("page", &page.to_string())]),
+    )
+    .await?
+    .response
+    .sections
+    .into_iter()
+    .flat_map(|section| section.hits)
+    .map(|x| x.result)
+    .collect())
+}
+
 pub async fn get_annotation(id: i32) -> Result<GeniusReferentResponse> {
     Ok(get_json::<GeniusReferentRequest>(
         SubDomain::Api,
@@ -104,7 +124,7 @@ pub async fn get_raw(
     path: &str,
     queries: Option<Vec<(&str, &str)>>,
 ) -> Result<(StatusCode, Bytes, HeaderMap)> {
-    let mut res = build_req(subdomain, path, queries).await?;
+    let mut res = build_req(subdomain, path, queries, TIMEOUT).await?;
     Ok((
         res.status(),
         res.body().limit(BODY_LIMIT).await?,
@@ -117,7 +137,7 @@ pub async fn get_text(
     path: &str,
     queries: Option<Vec<(&str, &str)>>,
 ) -> Result<String> {
-    let bytes = build_req(subdomain, path, queries)
+    let bytes = build_req(subdomain, path, queries, TIMEOUT)
         .await?
         .body()
         .await?
@@ -130,7 +150,7 @@ async fn get_json<T: DeserializeOwned>(
     path: &str,
     queries: Option<Vec<(&str, &str)>>,
 ) -> Result<T> {
-    let mut res = build_req(subdomain, path, queries).await?;
+    let mut res = build_req(subdomain, path, queries, TIMEOUT).await?;
     // We have to do this shit instead of just parsing as JSON since Genius,
     // at the time of writing, and as their name sarcasticly implies,
     // gives us a Content-Type of `application/html` for a JSON response!
@@ -140,12 +160,20 @@ async fn get_json<T: DeserializeOwned>(
 }
 
 // Default AWC timeout is 5 seconds (as of 9c70a88) which causes frequent timeouts.
-const TIMEOUT_SECS: u64 = 30;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Checks whether Genius is reachable, without reading the response.
+pub async fn ping(timeout: Duration) -> Result<()> {
+    build_req(SubDomain::Root, "", None, timeout)
+        .await
+        .map(|_| ())
+}
 
 async fn build_req(
     subdomain: SubDomain,
     path: &str,
     queries: Option<Vec<(&str, &str)>>,
+    timeout: Duration,
 ) -> Result<ClientResponse<Decompress<Payload>>> {
     let query_str = if let Some(q) = queries {
         String::from_iter(
@@ -169,15 +197,23 @@ async fn build_req(
         path.trim_start_matches('/'),
         query_str
     );
-    debug!("Sending request to {url}");
+    debug!("Sending request to {}", logging::url(&url));
+    logging::count_upstream_call();
 
+    let start = Instant::now();
     let res = Client::builder()
-        .timeout(Duration::from_secs(TIMEOUT_SECS))
+        .timeout(timeout)
         .add_default_header(("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/143.0.0.0 Safari/537.36"))
         .finish()
         .get(url)
         .send()
-        .await?;
+        .await;
+    metrics::observe_upstream(
+        subdomain.name(),
+        res.as_ref().ok().map(|res| res.status().as_u16()),
+        start.elapsed(),
+    );
+    let res = res?;
     let status = res.status();
 
     if status.is_client_error() || status.is_server_error() {
@@ -187,28 +223,11 @@ async fn build_req(
     }
 }
 
-static GENIUS_IMAGE_URL: &str = "https://images.genius.com/";
-static GENIUS_IMAGE_ALT_URL: &str = "https://images.rapgenius.com/";
-static GENIUS_BASE_PATTERN: Lazy<Regex> = lazy_regex!(r#"https?://\w*.?genius\.com"#);
-static YOUTUBE_URL: &str = "youtube.com/";
-static YOUTUBE_NOCOOKIE_URL: &str = "youtube-nocookie.com/";
-
-pub fn rewrite_links<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+pub fn sanitize_html<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let html = String::deserialize(deserializer)?;
-    let html = html.replace(
-        GENIUS_IMAGE_URL,
-        &format!("/api/image?url={GENIUS_IMAGE_URL}"),
-    ); // Images
-    let html = html.replace(
-        GENIUS_IMAGE_ALT_URL,
-        &format!("/api/image?url={GENIUS_IMAGE_URL}"),
-    ); // Images
-    let html = html.replace(YOUTUBE_URL, YOUTUBE_NOCOOKIE_URL); // YouTube no cookie
-    let html = GENIUS_BASE_PATTERN.replace_all(&html, ""); // We follow Genius' schema
-    Ok(html.to_string())
+    Ok(sanitize::sanitize(&String::deserialize(deserializer)?))
 }
 
 pub enum SubDomain {
@@ -218,6 +237,15 @@ pub enum SubDomain {
 }
 
 impl SubDomain {
+    /// A name for the subdomain in metrics and logs.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SubDomain::Api => "api",
+            SubDomain::Images => "images",
+            SubDomain::Root => "root",
+        }
+    }
+
     fn value(&self) -> &'static str {
         match *self {
             SubDomain::Images => "images.",
@@ -237,6 +265,21 @@ pub struct GeniusSearchResponse {
     pub hits: Vec<GeniusHit>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct GeniusSectionSearchRequest {
+    pub response: GeniusSectionSearchResponse,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GeniusSectionSearchResponse {
+    pub sections: Vec<GeniusSearchSection>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GeniusSearchSection {
+    pub hits: Vec<GeniusHit>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct GeniusReferentRequest {
     pub response: GeniusReferentResponse,
@@ -257,7 +300,7 @@ pub struct GeniusAnnotation {
 
 #[derive(Deserialize, Debug)]
 pub struct GeniusAnnotationBody {
-    #[serde(deserialize_with = "rewrite_links")]
+    #[serde(deserialize_with = "sanitize_html")]
     pub html: String,
 }
 
@@ -304,6 +347,42 @@ pub struct GeniusSong {
     pub album: Option<GeniusAlbum>,
     pub stats: GeniusStats,
     pub primary_artist: GeniusArtist,
+    #[serde(default)]
+    pub featured_artists: Vec<GeniusArtist>,
+    #[serde(default)]
+    pub producer_artists: Vec<GeniusArtist>,
+    #[serde(default)]
+    pub writer_artists: Vec<GeniusArtist>,
+    pub language: Option<String>,
+    #[serde(default)]
+    pub translation_songs: Vec<GeniusTranslationSong>,
+    #[serde(default)]
+    pub media: Vec<GeniusMedia>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GeniusMedia {
+    pub provider: String,
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GeniusTranslationSong {
+    pub id: u32,
+    pub language: String,
+    pub title: String,
+    pub path: String,
+}
+
+impl GeniusSong {
+    /// All artists credited on the song, starting with the primary artist.
+    pub fn credits(&self) -> Vec<&GeniusArtist> {
+        std::iter::once(&self.primary_artist)
+            .chain(&self.featured_artists)
+            .chain(&self.producer_artists)
+            .chain(&self.writer_artists)
+            .collect()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -363,7 +442,7 @@ pub struct GeniusArtist {
 
 #[derive(Deserialize, Debug)]
 pub struct GeniusDescription {
-    #[serde(deserialize_with = "rewrite_links")]
+    #[serde(deserialize_with = "sanitize_html")]
     pub html: String,
 }
 
@@ -373,6 +452,12 @@ pub struct ArtistSocial<'a> {
     pub brand: &'static str,
 }
 
+impl ArtistSocial<'_> {
+    pub fn url(&self) -> String {
+        format!("https://{}.com/{}", self.brand, encode(self.name_raw))
+    }
+}
+
 impl GeniusArtist {
     pub fn socials(&self) -> Vec<ArtistSocial<'_>> {
         let mut socials = Vec::with_capacity(3);
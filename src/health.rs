@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpResponse, Responder, get};
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use crate::genius;
+
+/// How long to wait for Genius to respond when checking readiness.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the result of a check is reused, so orchestrators polling us don't flood Genius.
+const PROBE_CACHE_DURATION: Duration = Duration::from_secs(30);
+
+/// The last check's result. It's locked while checking, so only one check runs at a time.
+static LAST_PROBE: Mutex<Option<(Instant, Result<(), String>)>> = Mutex::const_new(None);
+
+/// Whether the process is alive. Doesn't depend on anything else, so it's always cheap.
+#[get("/healthz")]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok()
+        .append_header(("Cache-Control", "no-store"))
+        .json(json!({ "status": "ok" }))
+}
+
+/// Whether the instance can serve pages.
+/// If `--readiness-probe` is set, this also checks that Genius is reachable.
+#[get("/readyz")]
+pub async fn readyz() -> impl Responder {
+    let (mut res, body) = if crate::args().readiness_probe {
+        match probe().await {
+            Ok(()) => (
+                HttpResponse::Ok(),
+                json!({ "status": "ready", "genius": "up" }),
+            ),
+            // We're fine, but can't serve pages until Genius is back.
+            Err(err) => (
+                HttpResponse::ServiceUnavailable(),
+                json!({ "status": "unavailable", "genius": "down", "error": err }),
+            ),
+        }
+    } else {
+        (HttpResponse::Ok(), json!({ "status": "ready" }))
+    };
+    res.append_header(("Cache-Control", "no-store")).json(body)
+}
+
+/// Checks whether Genius is reachable, reusing recent results.
+async fn probe() -> Result<(), String> {
+    // Concurrent requests wait for the check in progress and reuse its result.
+    let mut last_probe = LAST_PROBE.lock().await;
+    if let Some((checked, result)) = &*last_probe
+        && checked.elapsed() < PROBE_CACHE_DURATION
+    {
+        return result.clone();
+    }
+    let result = genius::ping(PROBE_TIMEOUT)
+        .await
+        .map_err(|err| err.to_string());
+    *last_probe = Some((Instant::now(), result.clone()));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn reports_status_as_json() {
+        let app = test::init_service(App::new().service(healthz).service(readyz)).await;
+        for (path, status) in [("/healthz", "ok"), ("/readyz", "ready")] {
+            let res =
+                test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+            assert!(res.status().is_success());
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["status"], status);
+        }
+    }
+}
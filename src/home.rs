@@ -11,7 +11,10 @@ struct HomeTemplate {
 
 #[get("/")]
 pub async fn home(req: HttpRequest) -> impl Responder {
-    template(HomeTemplate {
-        settings: settings_from_req(&req),
-    })
+    template(
+        &req,
+        HomeTemplate {
+            settings: settings_from_req(&req),
+        },
+    )
 }
@@ -0,0 +1,344 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io,
+    path::PathBuf,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use actix_web::web;
+use log::{info, warn};
+
+static IMAGE_CACHE: OnceLock<ImageCache> = OnceLock::new();
+
+const TEMP_EXTENSION: &str = "tmp";
+/// Numbers temporary files, so concurrent writes of the same image don't share one.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Opens the image cache if one is configured, loading any images already in it.
+pub fn init() -> io::Result<()> {
+    let args = crate::args();
+    if let Some(dir) = &args.image_cache_dir {
+        let cache = ImageCache::open(PathBuf::from(dir), args.image_cache_size * 1024 * 1024)?;
+        let _ = IMAGE_CACHE.set(cache);
+    }
+    Ok(())
+}
+
+/// Gets the image cache, if it's enabled.
+pub fn get() -> Option<&'static ImageCache> {
+    IMAGE_CACHE.get()
+}
+
+/// Gets the key of a variant of an image.
+/// Keys only contain characters that are safe to use in file names.
+///
+/// `("abc.1000x1000x1.png", "500", "webp")` -> `abc.1000x1000x1.png_500_webp`
+pub fn key(path: &str, variant: &str, format: &str) -> String {
+    format!("{path}_{variant}_{format}")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+pub struct CachedImage {
+    pub bytes: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+/// A size-limited on-disk cache of processed images.
+/// Once full, the least recently used images are evicted first.
+pub struct ImageCache {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    /// Keys ordered by when they were last used.
+    recently_used: BTreeMap<u64, String>,
+    size: u64,
+    tick: u64,
+}
+
+struct CacheEntry {
+    extension: &'static str,
+    size: u64,
+    tick: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recently_used.remove(&entry.tick);
+            entry.tick = tick;
+            self.recently_used.insert(tick, key.to_owned());
+        }
+    }
+
+    /// Inserts an entry, returning the one it replaced.
+    fn insert(&mut self, key: String, extension: &'static str, size: u64) -> Option<CacheEntry> {
+        let replaced = self.remove(&key);
+        self.tick += 1;
+        self.size += size;
+        self.recently_used.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                extension,
+                size,
+                tick: self.tick,
+            },
+        );
+        replaced
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.recently_used.remove(&entry.tick);
+        self.size -= entry.size;
+        Some(entry)
+    }
+}
+
+impl ImageCache {
+    fn open(dir: PathBuf, max_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let mut files = Vec::new();
+        for file in fs::read_dir(&dir)? {
+            let file = file?;
+            let path = file.path();
+            let Some((key, extension)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.rsplit_once('.'))
+            else {
+                continue;
+            };
+            if extension == TEMP_EXTENSION {
+                // Left over from a write that was interrupted.
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let Some(extension) = known_extension(extension) else {
+                continue;
+            };
+            let metadata = file.metadata()?;
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, key.to_owned(), extension, metadata.len()));
+        }
+
+        // Images used more recently were modified more recently.
+        files.sort_by_key(|(modified, ..)| *modified);
+        let mut state = CacheState::default();
+        for (_, key, extension, size) in files {
+            if let Some(replaced) = state.insert(key.clone(), extension, size)
+                && replaced.extension != extension
+            {
+                fs::remove_file(dir.join(format!("{key}.{}", replaced.extension)))?;
+            }
+        }
+        info!(
+            "Loaded {} cached images ({} MiB) from '{}'",
+            state.entries.len(),
+            state.size / 1024 / 1024,
+            dir.display()
+        );
+
+        let cache = ImageCache {
+            dir,
+            max_size,
+            state: Mutex::new(state),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    /// Gets an image from the cache, marking it as recently used.
+    pub async fn get(&'static self, key: String) -> Option<CachedImage> {
+        web::block(move || {
+            let extension = {
+                let mut state = self.state.lock().unwrap();
+                let extension = state.entries.get(&key)?.extension;
+                state.touch(&key);
+                extension
+            };
+            let path = self.path(&key, extension);
+            let bytes = fs::read(&path).ok()?;
+            // Keep track of when the image was last used across restarts.
+            let _ = File::options()
+                .append(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            Some(CachedImage {
+                bytes,
+                content_type: content_type(extension),
+            })
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Adds an image to the cache, evicting the least recently used images if it's full.
+    pub async fn insert(&'static self, key: String, bytes: Vec<u8>, content_type: &str) {
+        let Some(extension) = extension(content_type) else {
+            return;
+        };
+        let result = web::block(move || {
+            let temp_path = self.temp_path(&key);
+            if let Err(err) = fs::write(&temp_path, &bytes)
+                .and_then(|_| fs::rename(&temp_path, self.path(&key, extension)))
+            {
+                let _ = fs::remove_file(&temp_path);
+                return Err(err);
+            }
+            let replaced =
+                self.state
+                    .lock()
+                    .unwrap()
+                    .insert(key.clone(), extension, bytes.len() as u64);
+            if let Some(replaced) = replaced
+                && replaced.extension != extension
+            {
+                fs::remove_file(self.path(&key, replaced.extension))?;
+            }
+            self.evict();
+            io::Result::Ok(())
+        })
+        .await;
+        if let Ok(Err(err)) = result {
+            warn!("Failed to cache image: {err}");
+        }
+    }
+
+    fn evict(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.size > self.max_size {
+            let Some(key) = state
+                .recently_used
+                .first_key_value()
+                .map(|(_, k)| k.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = state.remove(&key) {
+                let _ = fs::remove_file(self.path(&key, entry.extension));
+            }
+        }
+    }
+
+    fn path(&self, key: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{extension}"))
+    }
+
+    /// Gets a path to write an image to before moving it into place, unique to this write.
+    fn temp_path(&self, key: &str) -> PathBuf {
+        let count = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.path(
+            key,
+            &format!("{}-{count}.{TEMP_EXTENSION}", std::process::id()),
+        )
+    }
+}
+
+fn known_extension(extension: &str) -> Option<&'static str> {
+    ["avif", "gif", "jpg", "png", "webp"]
+        .into_iter()
+        .find(|known| *known == extension)
+}
+
+fn extension(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "image/avif" => Some("avif"),
+        "image/gif" => Some("gif"),
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/webp" => Some("webp"),
+        _ => None,
+    }
+}
+
+fn content_type(extension: &str) -> &'static str {
+    match extension {
+        "avif" => "image/avif",
+        "gif" => "image/gif",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn key_is_file_name_safe() {
+        assert_eq!(
+            key("a.1000x1000x1.png", "500", "webp"),
+            "a.1000x1000x1.png_500_webp"
+        );
+        assert_eq!(key("../a b", "", "jpg"), "..-a-b__jpg");
+    }
+
+    #[test]
+    fn open_evicts_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("intellectual-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (i, name) in ["old.jpg", "new.webp", "partial.1-0.tmp"]
+            .iter()
+            .enumerate()
+        {
+            let path = dir.join(name);
+            fs::write(&path, [0; 10]).unwrap();
+            File::options()
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(i as u64))
+                .unwrap();
+        }
+
+        let cache = ImageCache::open(dir.clone(), 15).unwrap();
+        let state = cache.state.lock().unwrap();
+        assert_eq!(state.size, 10);
+        assert!(state.entries.contains_key("new"));
+        assert!(!dir.join("old.jpg").exists());
+        assert!(!dir.join("partial.1-0.tmp").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn temp_paths_are_unique() {
+        let cache = ImageCache {
+            dir: PathBuf::from("cache"),
+            max_size: 0,
+            state: Mutex::default(),
+        };
+        let path = cache.temp_path("a_500_webp");
+        assert_ne!(path, cache.temp_path("a_500_webp"));
+        assert_eq!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some(TEMP_EXTENSION)
+        );
+    }
+}
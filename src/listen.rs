@@ -0,0 +1,116 @@
+use std::{fmt, io, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use listenfd::ListenFd;
+use serde::{Serialize, Serializer};
+
+/// An address to listen on, either `<ip>:<port>` or `unix:<path>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("Expected a socket path after 'unix:'".into()),
+            Some(path) => Ok(Listen::Unix(PathBuf::from(path))),
+            None => s.parse().map(Listen::Tcp).map_err(|_| {
+                format!("Invalid address '{s}', expected '<ip>:<port>' or 'unix:<path>'")
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{addr}"),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for Listen {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A socket passed to us by the service manager.
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Takes the sockets passed by systemd socket activation, if any.
+pub fn systemd_listeners() -> io::Result<Vec<Listener>> {
+    let mut fds = ListenFd::from_env();
+    let mut listeners = Vec::with_capacity(fds.len());
+    for i in 0..fds.len() {
+        if let Some(listener) = fds.take_tcp_listener(i)? {
+            listeners.push(Listener::Tcp(listener));
+            continue;
+        }
+        #[cfg(unix)]
+        if let Some(listener) = fds.take_unix_listener(i)? {
+            listeners.push(Listener::Unix(listener));
+            continue;
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported socket passed at index {i}"),
+        ));
+    }
+    Ok(listeners)
+}
+
+/// Parses a Unix socket's permissions, in octal.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("Invalid permissions '{s}', expected an octal mode like '660'"))
+}
+
+/// Removes a socket left behind by a previous run, so it can be bound again.
+#[cfg(unix)]
+pub fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &std::path::Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            "[::1]:8080".parse::<Listen>().unwrap(),
+            Listen::Tcp("[::1]:8080".parse().unwrap())
+        );
+        let unix: Listen = "unix:/run/intellectual.sock".parse().unwrap();
+        assert_eq!(unix, Listen::Unix("/run/intellectual.sock".into()));
+        assert_eq!(unix.to_string(), "unix:/run/intellectual.sock");
+        assert!("localhost".parse::<Listen>().is_err());
+        assert!("unix:".parse::<Listen>().is_err());
+
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert!(parse_mode("999").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    hash::{BuildHasher, RandomState},
+    io::Write,
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use actix_web::{
+    Error, HttpMessage, HttpRequest,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue, USER_AGENT},
+    middleware::Next,
+};
+use clap::ValueEnum;
+use env_logger::Env;
+use log::{
+    info,
+    kv::{self, Key, Value, VisitSource},
+};
+use serde::Serialize;
+use serde_json::Map;
+
+use crate::rate_limit;
+
+static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Request IDs are derived from a counter, keyed randomly so they can't be guessed.
+static REQUEST_ID_KEY: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    /// The amount of requests sent to Genius while handling the current request.
+    static UPSTREAM_CALLS: Cell<u32>;
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// The ID of a request, shown on error pages so they can be matched to the logs.
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+pub fn init(format: LogFormat) {
+    let env = Env::default().filter_or("LOG_LEVEL", "info");
+    let mut builder = env_logger::builder();
+    builder
+        .parse_env(env)
+        .format_target(!std::env::var("LOG_TARGET").unwrap_or_default().is_empty());
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let mut fields = Map::new();
+            fields.insert("timestamp".into(), buf.timestamp().to_string().into());
+            fields.insert("level".into(), record.level().as_str().into());
+            fields.insert("target".into(), record.target().into());
+            fields.insert("message".into(), record.args().to_string().into());
+            let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+            writeln!(buf, "{}", serde_json::Value::Object(fields))
+        });
+    }
+    builder.init();
+}
+
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or_default();
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Gives every request an ID, and logs it if the access log is enabled.
+pub async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let args = crate::args();
+    let request_id = request_id(req.request());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let path = path(req.request()).into_owned();
+    let (ip, user_agent) = if args.log_private_data {
+        (
+            rate_limit::client_ip(req.request()).map(|ip| ip.to_string()),
+            req.headers()
+                .get(USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .map(str::to_owned),
+        )
+    } else {
+        (None, None)
+    };
+
+    let (res, upstream_calls) = UPSTREAM_CALLS
+        .scope(Cell::new(0), async {
+            let res = next.call(req).await;
+            (res, UPSTREAM_CALLS.with(Cell::get))
+        })
+        .await;
+    let mut res = res?;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER.clone(), value);
+    }
+
+    if args.access_log {
+        info!(
+            target: "access",
+            request_id = request_id.as_str(),
+            method = method.as_str(),
+            path = path.as_str(),
+            route = res.request().match_name().unwrap_or("other"),
+            status = res.status().as_u16(),
+            duration_ms = start.elapsed().as_secs_f64() * 1000.0,
+            upstream_calls = upstream_calls,
+            ip = ip.as_deref(),
+            user_agent = user_agent.as_deref();
+            "request"
+        );
+    }
+    Ok(res)
+}
+
+/// Counts a request to Genius towards the request being handled.
+pub fn count_upstream_call() {
+    let _ = UPSTREAM_CALLS.try_with(|calls| calls.set(calls.get() + 1));
+}
+
+/// Gets the ID of a request, using the one set by the reverse proxy if it's trusted.
+fn request_id(req: &HttpRequest) -> String {
+    if crate::args().trust_proxy_headers
+        && let Some(id) = req
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+        && is_valid_request_id(id)
+    {
+        return id.to_owned();
+    }
+    let count = REQUEST_COUNT.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}", REQUEST_ID_KEY.hash_one(count))
+}
+
+/// Whether a request ID is safe to put in logs and headers.
+fn is_valid_request_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Gets the path of a request to log.
+/// The query is only included if the instance logs private data, since it contains e.g. search queries.
+pub fn path(req: &HttpRequest) -> Cow<'_, str> {
+    if crate::args().log_private_data {
+        Cow::Owned(req.uri().to_string())
+    } else {
+        Cow::Borrowed(req.path())
+    }
+}
+
+/// Strips the query from a URL to log, unless the instance logs private data.
+pub fn url(url: &str) -> &str {
+    if crate::args().log_private_data {
+        url
+    } else {
+        url.split_once('?').map_or(url, |(url, _)| url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_request_ids() {
+        assert!(is_valid_request_id("7f3a-b2.c_1"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("a b"));
+        assert!(!is_valid_request_id("a\nINFO forged"));
+        assert!(!is_valid_request_id(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn strips_queries_by_default() {
+        assert_eq!(
+            url("https://genius.com/api/search?q=secret"),
+            "https://genius.com/api/search"
+        );
+    }
+}
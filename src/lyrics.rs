@@ -1,6 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::LazyLock;
 
+use actix_web::http::header::{self, HeaderValue};
 use actix_web::{HttpRequest, Responder, Result, get, web};
 use askama::Template;
 use futures::{StreamExt, future};
@@ -9,10 +10,15 @@ use futures::stream::FuturesUnordered;
 use scraper::{Html, Node, Selector};
 use serde::Deserialize;
 
+use crate::frontends::{self, Frontends};
 use crate::genius::{self, GeniusReferentResponse, GeniusSong};
+use crate::media::MediaLink;
+use crate::metrics;
+use crate::sanitize;
 use crate::settings::{Settings, settings_from_req};
-use crate::templates::template;
+use crate::templates::{self, template};
 use crate::utils;
+use crate::verse::{self, VerseHeader};
 
 static SONG_ID_SELECTOR: LazyLock<Selector> =
     LazyLock::new(|| Selector::parse("meta[property='twitter:app:url:iphone']").unwrap());
@@ -25,9 +31,16 @@ static LYRIC_EXCLUDES_SELECTOR: LazyLock<Selector> =
 #[derive(Default)]
 struct Verse<'a> {
     title: &'a str,
+    header: Option<VerseHeader>,
     lyrics: Vec<Lyric>,
 }
 
+impl Verse<'_> {
+    fn performer_color(&self) -> Option<usize> {
+        self.header.as_ref().and_then(|h| h.color)
+    }
+}
+
 enum Lyric {
     Text(TextLyric),
     Blank,
@@ -35,6 +48,8 @@ enum Lyric {
 
 struct TextLyric {
     parts: Vec<LyricPart>,
+    /// The IDs of annotations ending on this line, for showing them inline.
+    annotations_after: Vec<i32>,
 }
 
 struct LyricPart {
@@ -69,11 +84,39 @@ struct LyricsTemplate<'a> {
     annotations: Vec<Annotation>,
     path: &'a str,
     song: GeniusSong,
+    translation: Option<Translation<'a>>,
+    media: Vec<MediaLink>,
+    /// The URL of the media player the user chose to load.
+    embed_url: Option<String>,
+}
+
+impl LyricsTemplate<'_> {
+    fn annotation(&self, id: &i32) -> Option<&Annotation> {
+        self.annotations.iter().find(|a| a.id == *id)
+    }
+
+    /// Pairs each verse with the verse at the same position in the translation.
+    fn aligned_verses(&self) -> Vec<(Option<&Verse<'_>>, Option<&Verse<'_>>)> {
+        let translated = self.translation.as_ref().map_or(&[][..], |t| &t.verses);
+        (0..self.verses.len().max(translated.len()))
+            .map(|i| (self.verses.get(i), translated.get(i)))
+            .collect()
+    }
+}
+
+struct Translation<'a> {
+    id: u32,
+    language: String,
+    verses: Vec<Verse<'a>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LyricsQuery {
     id: Option<u32>,
+    /// The song ID of a translation to show side-by-side with the original.
+    translation: Option<u32>,
+    /// The provider of the media player to load.
+    media: Option<String>,
 }
 
 #[get("/{path}-lyrics")]
@@ -103,15 +146,142 @@ pub async fn lyrics(req: HttpRequest, info: web::Query<LyricsQuery>) -> Result<i
         song = genius::get_song(id).await?;
     }
 
-    let (verses, annotations) = scrape_lyrics(&document).await?;
+    // Only translations linked from the original song can be shown alongside it.
+    let translation_song = info
+        .translation
+        .and_then(|id| song.translation_songs.iter().find(|t| t.id == id));
+    let translation_document = match translation_song {
+        Some(translation) => Some(Html::parse_document(
+            &genius::get_text(genius::SubDomain::Root, &translation.path, None).await?,
+        )),
+        None => None,
+    };
+
+    let ((mut verses, mut annotations), translated) =
+        future::try_join(scrape_lyrics(&document), async {
+            match &translation_document {
+                Some(document) => scrape_lyrics(document).await.map(Some),
+                None => Ok(None),
+            }
+        })
+        .await?;
+
+    let settings = settings_from_req(&req);
+
+    let mut translation = match (translation_song, translated) {
+        (Some(translation_song), Some((translated_verses, translated_annotations))) => {
+            annotations.extend(translated_annotations);
+            Some(Translation {
+                id: translation_song.id,
+                language: translation_song.language.clone(),
+                verses: translated_verses,
+            })
+        }
+        _ => None,
+    };
+
+    let credits = song.credits();
+    for verse in verses
+        .iter_mut()
+        .chain(translation.iter_mut().flat_map(|t| t.verses.iter_mut()))
+    {
+        verse.header = VerseHeader::parse(verse.title, &credits);
+    }
+    if settings.color_performers {
+        verse::assign_colors(verses.iter_mut().filter_map(|v| v.header.as_mut()));
+        if let Some(translation) = translation.as_mut() {
+            verse::assign_colors(
+                translation
+                    .verses
+                    .iter_mut()
+                    .filter_map(|v| v.header.as_mut()),
+            );
+        }
+    }
+
+    if settings.inline_annotations() {
+        mark_annotation_ends(&mut verses);
+        if let Some(translation) = translation.as_mut() {
+            mark_annotation_ends(&mut translation.verses);
+        }
+    }
+
+    let frontends = Frontends::for_settings(&settings);
+    for annotation in annotations.iter_mut() {
+        annotation.body = sanitize::personalize(&annotation.body, &settings, &frontends);
+    }
+
+    let media: Vec<MediaLink> = song
+        .media
+        .iter()
+        .filter_map(|media| MediaLink::new(media, &frontends))
+        .collect();
+    // Players are only loaded when asked for so no third-party requests are made until then.
+    let embed_url = info
+        .media
+        .as_ref()
+        .filter(|_| settings.embed_media)
+        .and_then(|provider| media.iter().find(|m| &m.provider == provider))
+        .and_then(|media| media.embed_url.clone());
+    let frame_origin = embed_url
+        .as_deref()
+        .and_then(frontends::origin)
+        .map(str::to_owned);
+
+    let mut res = template(
+        &req,
+        LyricsTemplate {
+            settings,
+            verses,
+            annotations,
+            path,
+            song,
+            translation,
+            media,
+            embed_url,
+        },
+    );
+    // Keep any policy the template set, e.g. to allow a custom theme.
+    let csp = res
+        .headers()
+        .get(header::CONTENT_SECURITY_POLICY)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or(templates::CONTENT_SECURITY_POLICY)
+        .to_owned();
+    if let Some(origin) = frame_origin
+        && let Ok(value) = HeaderValue::from_str(&templates::content_security_policy_with_frame(
+            &csp, &origin,
+        ))
+    {
+        res.headers_mut()
+            .insert(header::CONTENT_SECURITY_POLICY, value);
+    }
+    Ok(res)
+}
+
+/// Marks the line each annotation ends on, so it can be shown inline below it.
+fn mark_annotation_ends(verses: &mut [Verse]) {
+    let mut lines: Vec<&mut TextLyric> = verses
+        .iter_mut()
+        .flat_map(|v| v.lyrics.iter_mut())
+        .filter_map(|l| match l {
+            Lyric::Text(tl) => Some(tl),
+            _ => None,
+        })
+        .collect();
 
-    Ok(template(LyricsTemplate {
-        settings: settings_from_req(&req),
-        verses,
-        annotations,
-        path,
-        song,
-    }))
+    let mut ends = HashMap::new();
+    for (i, line) in lines.iter().enumerate() {
+        for annotation in line.parts.iter().filter_map(|p| p.annotation.as_ref()) {
+            ends.insert(annotation.id, i);
+        }
+    }
+    for (id, i) in ends {
+        lines[i].annotations_after.push(id);
+    }
+    for line in lines {
+        line.annotations_after.sort_unstable();
+    }
 }
 
 fn get_song_id(document: &Html) -> crate::Result<u32> {
@@ -185,7 +355,7 @@ async fn scrape_lyrics(document: &Html) -> crate::Result<(Vec<Verse<'_>>, Vec<An
                     }
                     current_verse = Some(Verse {
                         title: text,
-                        lyrics: Vec::new(),
+                        ..Default::default()
                     });
                 } else {
                     let last = curr.lyrics.last_mut();
@@ -195,6 +365,7 @@ async fn scrape_lyrics(document: &Html) -> crate::Result<(Vec<Verse<'_>>, Vec<An
                                 text: text.to_string(),
                                 annotation: curr_annotation.clone(),
                             }],
+                            annotations_after: Vec::new(),
                         }));
                         new_line = false;
                     } else if let Some(Lyric::Text(text_lyric)) = last {
@@ -214,16 +385,18 @@ async fn scrape_lyrics(document: &Html) -> crate::Result<(Vec<Verse<'_>>, Vec<An
     } else {
         verses.push(Verse {
             title: "",
+            header: None,
             lyrics: vec![Lyric::Text(TextLyric {
                 parts: vec![LyricPart {
                     text: "This song has no lyrics.".to_owned(),
                     annotation: None,
                 }],
+                annotations_after: Vec::new(),
             })],
         })
     }
 
-    let annotations: HashMap<i32, GeniusReferentResponse> = verses
+    let annotation_ids: Vec<i32> = verses
         .iter()
         .flat_map(|v| {
             v.lyrics
@@ -239,6 +412,11 @@ async fn scrape_lyrics(document: &Html) -> crate::Result<(Vec<Verse<'_>>, Vec<An
                         .filter_map(|p| p.annotation.as_ref().map(|a| a.id))
                 })
         })
+        .collect();
+    metrics::observe_annotations(annotation_ids.len());
+
+    let annotations: HashMap<i32, GeniusReferentResponse> = annotation_ids
+        .into_iter()
         .map(genius::get_annotation)
         .collect::<FuturesUnordered<_>>()
         .collect::<Vec<_>>()
@@ -1,81 +1,262 @@
 #![forbid(unsafe_code)]
 
-use std::{env, error::Error, fs::File, io::BufReader, process::exit, time::Duration};
+use std::{env, error::Error, net::SocketAddr, process::exit, sync::OnceLock, time::Duration};
 
 use actix_web::{App, HttpServer, http::StatusCode, middleware};
-use clap::Parser;
-use env_logger::Env;
+use clap::{Parser, Subcommand};
 use log::{error, info, warn};
-use rustls::{Certificate, PrivateKey, ServerConfig as RustlsServerConfig};
+use serde::Serialize;
+
+use crate::frontends::Frontend;
+use crate::listen::{Listen, Listener};
+use crate::logging::LogFormat;
+use crate::settings::GifMode;
 
 mod album;
 mod api;
 mod artist;
+mod config;
 mod errors;
+mod frontends;
 mod genius;
+mod health;
 mod home;
+mod image_cache;
+mod listen;
+mod logging;
 mod lyrics;
+mod media;
+mod metrics;
+mod rate_limit;
 mod resource;
+mod sanitize;
 mod search;
 mod settings;
 mod templates;
+mod tls;
 mod utils;
+mod verse;
 
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Serialize, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+
+    /// A TOML file to read options from.
+    /// Options set on the command line or in environment variables take precedence.
+    #[arg(long, env = "INTELLECTUAL_CONFIG")]
+    #[serde(skip)]
+    config: Option<String>,
+
     /// Sets the address to listen on
-    #[arg(short, long, default_value = "0.0.0.0")]
+    #[arg(short, long, default_value = "0.0.0.0", env = "INTELLECTUAL_ADDRESS")]
     address: String,
 
-    /// Sets the port to listen on. Will be overridden by the PORT env var if present
-    #[arg(short, long, default_value_t = 8080)]
+    /// Sets the port to listen on. The PORT env var is also used if INTELLECTUAL_PORT isn't set
+    #[arg(short, long, default_value_t = 8080, env = "INTELLECTUAL_PORT")]
     port: u16,
 
+    /// An address to listen on, either '<ip>:<port>' or 'unix:<path>'.
+    /// Can be specified multiple times, or comma-separated. Overrides the address and port.
+    /// Sockets passed by systemd socket activation are used instead if present.
+    #[arg(long, env = "INTELLECTUAL_LISTEN", value_delimiter = ',')]
+    listen: Vec<Listen>,
+
+    /// The permissions of Unix sockets, in octal.
+    #[arg(long, default_value = "660", value_parser = listen::parse_mode, env = "INTELLECTUAL_UNIX_SOCKET_MODE")]
+    #[serde(serialize_with = "config::octal")]
+    unix_socket_mode: u32,
+
     /// The amount of HTTP workers to use. 0 to equal physical CPU cores
-    #[arg(short, long, default_value_t = 0)]
+    #[arg(short, long, default_value_t = 0, env = "INTELLECTUAL_WORKERS")]
     workers: usize,
 
     /// The Keep-Alive timeout, in seconds. Set to 0 to disable.
-    #[arg(short, long, default_value_t = 15.0)]
+    #[arg(
+        short,
+        long,
+        default_value_t = 15.0,
+        env = "INTELLECTUAL_KEEP_ALIVE_TIMEOUT"
+    )]
     keep_alive_timeout: f32,
 
     /// Whether TLS should be used
-    #[arg(long, default_value = "false")]
+    #[arg(long, default_value = "false", env = "INTELLECTUAL_TLS")]
     tls: bool,
 
     /// The path to the KEY file. Required when using TLS.
-    #[arg(long, required_if_eq("tls", "true"))]
+    #[arg(long, required_if_eq("tls", "true"), env = "INTELLECTUAL_TLS_KEY_FILE")]
     tls_key_file: Option<String>,
 
     /// The path to the CERT file. Required when using TLS.
-    #[arg(long, required_if_eq("tls", "true"))]
+    #[arg(
+        long,
+        required_if_eq("tls", "true"),
+        env = "INTELLECTUAL_TLS_CERT_FILE"
+    )]
     tls_cert_file: Option<String>,
+
+    /// A port to listen for plain HTTP on, redirecting requests to HTTPS. Requires TLS.
+    #[arg(long, requires = "tls", env = "INTELLECTUAL_TLS_REDIRECT_PORT")]
+    tls_redirect_port: Option<u16>,
+
+    /// A webroot to serve ACME HTTP-01 challenges from on the redirect port,
+    /// e.g. for certbot's webroot plugin.
+    #[arg(
+        long,
+        requires = "tls_redirect_port",
+        env = "INTELLECTUAL_ACME_CHALLENGE_DIR"
+    )]
+    acme_challenge_dir: Option<String>,
+
+    /// The format to log in.
+    #[arg(
+        long,
+        value_enum,
+        default_value = "text",
+        env = "INTELLECTUAL_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// Log every request, with its route, status, duration and requests sent to Genius.
+    #[arg(long, default_value = "false", env = "INTELLECTUAL_ACCESS_LOG")]
+    access_log: bool,
+
+    /// Include query strings, IP addresses and user agents in logs.
+    /// These are left out by default to protect users' privacy.
+    #[arg(long, default_value = "false", env = "INTELLECTUAL_LOG_PRIVATE_DATA")]
+    log_private_data: bool,
+
+    /// Check that Genius is reachable in /readyz, so it reports when Genius is down.
+    #[arg(long, default_value = "false", env = "INTELLECTUAL_READINESS_PROBE")]
+    readiness_probe: bool,
+
+    /// Serve Prometheus metrics at /metrics.
+    #[arg(long, default_value = "false", env = "INTELLECTUAL_METRICS")]
+    metrics: bool,
+
+    /// Serve metrics on a separate address instead, e.g. '127.0.0.1:9100', so they aren't public.
+    #[arg(long, requires = "metrics", env = "INTELLECTUAL_METRICS_ADDRESS")]
+    metrics_address: Option<SocketAddr>,
+
+    /// A frontend to redirect a service's links to, e.g. 'youtube=https://yewtu.be'.
+    /// Can be specified multiple times, or comma-separated. Users can override these in their settings.
+    #[arg(long, env = "INTELLECTUAL_FRONTEND", value_delimiter = ',')]
+    frontend: Vec<Frontend>,
+
+    /// A directory to cache proxied images in. Caching is disabled if not set.
+    #[arg(long, env = "INTELLECTUAL_IMAGE_CACHE_DIR")]
+    image_cache_dir: Option<String>,
+
+    /// The maximum size of the image cache, in MiB.
+    /// The least recently used images are evicted once it's full.
+    #[arg(long, default_value_t = 512, env = "INTELLECTUAL_IMAGE_CACHE_SIZE")]
+    image_cache_size: u64,
+
+    /// A directory of static files that override or add to the built-in ones,
+    /// e.g. extra themes with an extended `style/theme/themes.json`, a `logo.svg` or `logo.png`,
+    /// and a `home.html` snippet shown on the home page.
+    #[arg(long, env = "INTELLECTUAL_STATIC_OVERRIDE_DIR")]
+    static_override_dir: Option<String>,
+
+    /// The quality to encode proxied images with, from 1 to 100.
+    #[arg(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100), env = "INTELLECTUAL_IMAGE_QUALITY")]
+    image_quality: u8,
+
+    /// Pass proxied images through as-is, without resizing or transcoding them.
+    #[arg(
+        long,
+        default_value = "false",
+        env = "INTELLECTUAL_DISABLE_IMAGE_TRANSCODING"
+    )]
+    disable_image_transcoding: bool,
+
+    /// How animated GIFs are shown by default, either 'animate', 'still' or 'webp'.
+    /// Still GIFs only load their first frame, linking to the animated GIF.
+    /// WebP converts GIFs to animated WebP for clients that support it, which is much smaller.
+    /// Users can override this in their settings.
+    #[arg(long, default_value = "animate", env = "INTELLECTUAL_GIF_MODE")]
+    gif_mode: GifMode,
+
+    /// The maximum amount of images a single client can request per minute.
+    /// Rate limiting is disabled if not set.
+    #[arg(long, env = "INTELLECTUAL_IMAGE_RATE_LIMIT")]
+    image_rate_limit: Option<u32>,
+
+    /// A secret key to sign image URLs with.
+    /// If set, only images linked by this instance can be proxied.
+    #[serde(serialize_with = "config::redact")]
+    #[arg(long, env = "INTELLECTUAL_IMAGE_SIGNING_KEY")]
+    image_signing_key: Option<String>,
+
+    /// Whether to trust the Forwarded and X-Forwarded-For headers for client IPs.
+    /// Only enable this when running behind a reverse proxy that sets them.
+    #[arg(
+        long,
+        default_value = "false",
+        env = "INTELLECTUAL_TRUST_PROXY_HEADERS"
+    )]
+    trust_proxy_headers: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the instance's configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Validate the configuration and print the effective configuration
+    Check,
+}
+
+static ARGS: OnceLock<Args> = OnceLock::new();
+
+/// Gets the arguments the instance was started with.
+/// Falls back to the defaults if they haven't been parsed, e.g. in tests.
+fn args() -> &'static Args {
+    ARGS.get_or_init(|| {
+        config::try_parse_from([env!("CARGO_PKG_NAME")]).expect("Invalid default configuration")
+    })
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let env = Env::default().filter_or("LOG_LEVEL", "info");
-    env_logger::builder()
-        .parse_env(env)
-        .format_target(!env::var("LOG_TARGET").unwrap_or_default().is_empty())
-        .init();
-
-    let args = Args::parse();
-
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| args.port.to_string())
-        .parse::<u16>()
-        .unwrap();
-
-    info!(
-        "Starting Intellectual v{}, listening on {}:{}!",
-        env!("IN_VERSION"),
-        args.address,
-        port
-    );
+    let args = ARGS.get_or_init(config::parse);
+    logging::init(args.log_format);
+
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = &args.command
+    {
+        exit(config::check(args));
+    }
+
+    info!("Starting Intellectual v{}!", env!("IN_VERSION"));
+
+    if let Err(err) = image_cache::init() {
+        error!(
+            "Failed to open image cache '{}': {err}",
+            args.image_cache_dir.as_deref().unwrap_or_default()
+        );
+        exit(1);
+    }
+
+    if let Err(err) = resource::init() {
+        error!(
+            "Failed to load static overrides from '{}': {err}",
+            args.static_override_dir.as_deref().unwrap_or_default()
+        );
+        exit(1);
+    }
 
     let mut server = HttpServer::new(|| {
         App::new()
@@ -86,6 +267,8 @@ async fn main() -> std::io::Result<()> {
                     .handler(StatusCode::BAD_REQUEST, errors::render_400),
             )
             .wrap(middleware::Compress::default())
+            .wrap(middleware::from_fn(metrics::track))
+            .wrap(middleware::from_fn(logging::access_log))
             .wrap(
                 middleware::DefaultHeaders::new()
                     .add(("Referrer-Policy", "no-referrer"))
@@ -93,18 +276,28 @@ async fn main() -> std::io::Result<()> {
                     .add(("X-Content-Type-Options", "nosniff"))
                     .add((
                         "Content-Security-Policy",
-                        "default-src 'self'; frame-src https://www.youtube-nocookie.com/embed/",
+                        templates::CONTENT_SECURITY_POLICY,
                     )),
             )
             // Routes
+            .configure(|cfg| {
+                let args = crate::args();
+                if args.metrics && args.metrics_address.is_none() {
+                    cfg.service(metrics::metrics);
+                }
+            })
             .service(album::album)
             .service(api::image)
             .service(artist::artist)
+            .service(health::healthz)
+            .service(health::readyz)
             .service(home::home)
             .service(lyrics::lyrics)
             .service(search::search)
             .service(settings::settings)
             .service(settings::settings_form)
+            .service(settings::restore)
+            .service(settings::restore_form)
             // Static Resources
             .service(resource::resource)
     });
@@ -119,64 +312,82 @@ async fn main() -> std::io::Result<()> {
         server = server.workers(args.workers);
     }
 
-    if args.tls {
-        // To create a self-signed temporary cert for testing:
-        // openssl req -x509 -newkey rsa:4096 -nodes -keyout key.pem -out cert.pem -days 365 -subj '/CN=localhost'
-        server.bind_rustls_021((args.address.to_owned(), port), build_tls_config(&args)?)
-    } else {
-        server.bind_auto_h2c((args.address, port))
-    }?
-    .run()
-    .await
-}
+    // To create a self-signed temporary cert for testing:
+    // openssl req -x509 -newkey rsa:4096 -nodes -keyout key.pem -out cert.pem -days 365 -subj '/CN=localhost'
+    let tls_config = match (&args.tls_cert_file, &args.tls_key_file) {
+        (Some(cert), Some(key)) if args.tls => match tls::server_config(cert, key) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                error!("{err}");
+                exit(1);
+            }
+        },
+        _ => None,
+    };
 
-fn build_tls_config(args: &Args) -> std::io::Result<RustlsServerConfig> {
-    Ok(RustlsServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(create_cert_chain(args), PrivateKey(create_key(args)))
-        .unwrap())
-}
+    let systemd_listeners = listen::systemd_listeners()?;
+    let socket_activated = !systemd_listeners.is_empty();
+    if socket_activated {
+        info!(
+            "Listening on {} socket(s) passed by systemd",
+            systemd_listeners.len()
+        );
+    }
+    for listener in systemd_listeners {
+        server = match listener {
+            Listener::Tcp(listener) => match &tls_config {
+                Some(config) => server.listen_rustls_0_21(listener, config.clone()),
+                None => server.listen_auto_h2c(listener),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => server.listen_uds(listener),
+        }?;
+    }
 
-fn create_cert_chain(args: &Args) -> Vec<Certificate> {
-    let cert_file_path = args.tls_cert_file.as_ref().unwrap();
-    let cert_file = &mut BufReader::new(match File::open(cert_file_path) {
-        Ok(file) => file,
-        Err(err) => {
-            error!("Failed to load cert file '{cert_file_path}': {err}");
-            exit(1);
+    if !socket_activated && args.listen.is_empty() {
+        info!("Listening on {}:{}", args.address, args.port);
+        let addr = (args.address.to_owned(), args.port);
+        server = match &tls_config {
+            Some(config) => server.bind_rustls_021(addr, config.clone()),
+            None => server.bind_auto_h2c(addr),
+        }?;
+    } else if !socket_activated {
+        for address in args.listen.clone() {
+            info!("Listening on {address}");
+            server = match address {
+                Listen::Tcp(addr) => match &tls_config {
+                    Some(config) => server.bind_rustls_021(addr, config.clone()),
+                    None => server.bind_auto_h2c(addr),
+                },
+                #[cfg(unix)]
+                Listen::Unix(path) => {
+                    listen::remove_stale_socket(&path)?;
+                    let server = server.bind_uds(&path)?;
+                    listen::set_mode(&path, args.unix_socket_mode)?;
+                    Ok(server)
+                }
+                #[cfg(not(unix))]
+                Listen::Unix(_) => Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unix sockets aren't supported on this platform",
+                )),
+            }?;
         }
-    });
-
-    let cert_chain: Vec<Certificate> = rustls_pemfile::certs(cert_file)
-        .unwrap()
-        .into_iter()
-        .map(Certificate)
-        .collect();
-    if cert_chain.is_empty() {
-        error!("Failed to find any certs in '{cert_file_path}'");
-        exit(1);
     }
-    cert_chain
-}
 
-fn create_key(args: &Args) -> Vec<u8> {
-    let key_file_path = args.tls_key_file.as_ref().unwrap();
-    let key_file = &mut BufReader::new(match File::open(key_file_path) {
-        Ok(file) => file,
-        Err(err) => {
-            error!("Failed to load key file '{key_file_path}': {err}");
-            exit(1);
+    // Unix sockets have no address, so this is the first TCP listener.
+    let https_addr = server.addrs().first().copied();
+    let mut servers = vec![server.run()];
+    if let Some(port) = args.tls_redirect_port
+        && args.tls
+    {
+        match https_addr {
+            Some(addr) => servers.push(tls::redirect_server(addr, port)?),
+            None => warn!("Not redirecting HTTP requests to HTTPS, since there's no TCP listener"),
         }
-    });
-    let mut keys: Vec<Vec<u8>> = rustls_pemfile::pkcs8_private_keys(key_file).unwrap();
-    if keys.is_empty() {
-        error!("Failed to find any keys in '{key_file_path}'");
-        exit(1);
     }
-    if keys.len() > 1 {
-        warn!("Found multiple keys in '{key_file_path}'! Only the first will be used.");
+    if let Some(addr) = args.metrics_address {
+        servers.push(metrics::server(addr)?);
     }
-
-    keys.remove(0)
+    futures::future::try_join_all(servers).await.map(|_| ())
 }
@@ -0,0 +1,104 @@
+use urlencoding::encode;
+
+use crate::frontends::{Frontends, Service};
+use crate::genius::GeniusMedia;
+
+const YOUTUBE_EMBED_URL: &str = "https://www.youtube-nocookie.com";
+const SOUNDCLOUD_EMBED_URL: &str = "https://w.soundcloud.com/player/?url=";
+const SPOTIFY_URL: &str = "https://open.spotify.com/";
+
+/// A song's media on an external provider.
+pub struct MediaLink {
+    pub provider: String,
+    pub name: String,
+    /// The outbound link, redirected to the user's frontend if they have one.
+    pub url: String,
+    /// The URL of the provider's embeddable player, if it has one.
+    pub embed_url: Option<String>,
+}
+
+impl MediaLink {
+    /// Creates a link to a song's media, or `None` if its URL isn't an HTTP(S) link.
+    pub fn new(media: &GeniusMedia, frontends: &Frontends) -> Option<Self> {
+        let scheme = media.url.split_once(':')?.0;
+        if !["http", "https"]
+            .into_iter()
+            .any(|allowed| scheme.eq_ignore_ascii_case(allowed))
+        {
+            return None;
+        }
+
+        let embed_url = match media.provider.as_str() {
+            "youtube" => youtube_id(&media.url).map(|id| {
+                format!(
+                    "{}/embed/{id}",
+                    frontends.get(Service::YouTube).unwrap_or(YOUTUBE_EMBED_URL)
+                )
+            }),
+            "soundcloud" => Some(format!("{SOUNDCLOUD_EMBED_URL}{}", encode(&media.url))),
+            "spotify" => media
+                .url
+                .strip_prefix(SPOTIFY_URL)
+                .map(|path| format!("{SPOTIFY_URL}embed/{path}")),
+            _ => None,
+        };
+
+        Some(MediaLink {
+            provider: media.provider.clone(),
+            name: provider_name(&media.provider),
+            url: frontends.redirect(&media.url),
+            embed_url,
+        })
+    }
+}
+
+fn provider_name(provider: &str) -> String {
+    match provider {
+        "youtube" => "YouTube".into(),
+        "soundcloud" => "SoundCloud".into(),
+        "spotify" => "Spotify".into(),
+        "apple_music" => "Apple Music".into(),
+        _ => provider.replace('_', " "),
+    }
+}
+
+/// Gets the video ID from a YouTube link.
+///
+/// `http://www.youtube.com/watch?v=id` -> `id`
+/// `https://youtu.be/id` -> `id`
+fn youtube_id(url: &str) -> Option<&str> {
+    let id = match url.split_once("youtu.be/") {
+        Some((_, rest)) => rest,
+        None => url
+            .split_once('?')?
+            .1
+            .split('&')
+            .find_map(|query| query.strip_prefix("v="))?,
+    };
+    let id = id.split(['?', '&', '#']).next()?;
+    (!id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    .then_some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_http_links() {
+        let link = |url: &str| {
+            let media = GeniusMedia {
+                provider: "youtube".into(),
+                url: url.into(),
+            };
+            MediaLink::new(&media, Frontends::instance())
+        };
+        assert!(link("https://www.youtube.com/watch?v=id").is_some());
+        assert!(link("HTTP://youtu.be/id").is_some());
+        assert!(link("javascript:alert(1)").is_none());
+        assert!(link("//example.com").is_none());
+    }
+}
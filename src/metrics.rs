@@ -0,0 +1,298 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    App, Error, HttpResponse, HttpServer, Responder,
+    body::MessageBody,
+    dev::{Server, ServiceRequest, ServiceResponse},
+    get,
+    middleware::Next,
+};
+use log::info;
+
+/// Buckets for durations, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+/// Buckets for the amount of annotations fetched for a lyrics page.
+const ANNOTATION_BUCKETS: &[f64] = &[0.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0];
+
+static REQUESTS: Counter = Counter::new(
+    "intellectual_requests_total",
+    "Requests handled, by route and status.",
+);
+static REQUEST_DURATION: Histogram = Histogram::new(
+    "intellectual_request_duration_seconds",
+    "How long requests took to handle, by route.",
+    DURATION_BUCKETS,
+);
+static UPSTREAM_REQUESTS: Counter = Counter::new(
+    "intellectual_upstream_requests_total",
+    "Requests sent to Genius, by subdomain and status.",
+);
+static UPSTREAM_DURATION: Histogram = Histogram::new(
+    "intellectual_upstream_request_duration_seconds",
+    "How long requests to Genius took, by subdomain.",
+    DURATION_BUCKETS,
+);
+static ANNOTATIONS: Histogram = Histogram::new(
+    "intellectual_lyrics_annotations",
+    "Annotations fetched for each lyrics page.",
+    ANNOTATION_BUCKETS,
+);
+static IMAGE_BYTES: Counter = Counter::new(
+    "intellectual_image_proxy_bytes_total",
+    "Bytes of images fetched from Genius (in) and sent to clients (out).",
+);
+static IMAGE_TRANSCODE_DURATION: Histogram = Histogram::new(
+    "intellectual_image_transcode_duration_seconds",
+    "How long proxied images took to decode, resize and encode.",
+    DURATION_BUCKETS,
+);
+static IMAGE_CACHE: Counter = Counter::new(
+    "intellectual_image_cache_requests_total",
+    "Image cache lookups, by whether they were a hit or a miss.",
+);
+
+/// Records the route, status and duration of every request.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let res = next.call(req).await?;
+    // Routes are named after their handler.
+    let route = res.request().match_name().unwrap_or("other");
+    REQUESTS.add(
+        &format!(r#"route="{route}",status="{}""#, res.status().as_u16()),
+        1,
+    );
+    REQUEST_DURATION.observe(
+        &format!(r#"route="{route}""#),
+        start.elapsed().as_secs_f64(),
+    );
+    Ok(res)
+}
+
+/// Records a request to Genius. The status is `None` if no response was received.
+pub fn observe_upstream(subdomain: &str, status: Option<u16>, duration: Duration) {
+    let status = status.map_or_else(|| "error".to_owned(), |status| status.to_string());
+    UPSTREAM_REQUESTS.add(&format!(r#"subdomain="{subdomain}",status="{status}""#), 1);
+    UPSTREAM_DURATION.observe(
+        &format!(r#"subdomain="{subdomain}""#),
+        duration.as_secs_f64(),
+    );
+}
+
+pub fn observe_annotations(count: usize) {
+    ANNOTATIONS.observe("", count as f64);
+}
+
+pub fn add_image_bytes_in(bytes: usize) {
+    IMAGE_BYTES.add(r#"direction="in""#, bytes as u64);
+}
+
+pub fn add_image_bytes_out(bytes: usize) {
+    IMAGE_BYTES.add(r#"direction="out""#, bytes as u64);
+}
+
+pub fn observe_transcode(duration: Duration) {
+    IMAGE_TRANSCODE_DURATION.observe("", duration.as_secs_f64());
+}
+
+pub fn observe_image_cache(hit: bool) {
+    IMAGE_CACHE.add(
+        if hit {
+            r#"result="hit""#
+        } else {
+            r#"result="miss""#
+        },
+        1,
+    );
+}
+
+/// Serves the metrics in Prometheus' text format.
+#[get("/metrics")]
+pub async fn metrics() -> impl Responder {
+    let mut out = String::new();
+    REQUESTS.render(&mut out);
+    REQUEST_DURATION.render(&mut out);
+    UPSTREAM_REQUESTS.render(&mut out);
+    UPSTREAM_DURATION.render(&mut out);
+    ANNOTATIONS.render(&mut out);
+    IMAGE_BYTES.render(&mut out);
+    IMAGE_TRANSCODE_DURATION.render(&mut out);
+    IMAGE_CACHE.render(&mut out);
+    HttpResponse::Ok()
+        .append_header(("Content-Type", "text/plain; version=0.0.4"))
+        .append_header(("Cache-Control", "no-store"))
+        .body(out)
+}
+
+/// Creates a server that only serves metrics, so they aren't exposed to the public.
+pub fn server(addr: SocketAddr) -> io::Result<Server> {
+    info!("Serving metrics on {addr}");
+    Ok(HttpServer::new(|| App::new().service(metrics))
+        .workers(1)
+        .bind(addr)?
+        .run())
+}
+
+/// A counter, with a value for each set of labels.
+struct Counter {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn add(&self, labels: &str, value: u64) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(labels.to_owned())
+            .or_default() += value;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {value}", self.name, braces(labels));
+        }
+    }
+}
+
+/// A histogram, with a distribution for each set of labels.
+struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<String, Distribution>>,
+}
+
+#[derive(Default)]
+struct Distribution {
+    /// The amount of observations in each bucket, not including those in smaller buckets.
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str, buckets: &'static [f64]) -> Self {
+        Histogram {
+            name,
+            help,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn observe(&self, labels: &str, value: f64) {
+        let mut values = self.values.lock().unwrap();
+        let distribution = values.entry(labels.to_owned()).or_default();
+        distribution.counts.resize(self.buckets.len(), 0);
+        if let Some(bucket) = self.buckets.iter().position(|le| value <= *le) {
+            distribution.counts[bucket] += 1;
+        }
+        distribution.count += 1;
+        distribution.sum += value;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        for (labels, distribution) in self.values.lock().unwrap().iter() {
+            let separator = if labels.is_empty() { "" } else { "," };
+            let mut cumulative = 0;
+            for (le, count) in self.buckets.iter().zip(&distribution.counts) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    r#"{}_bucket{{{labels}{separator}le="{le}"}} {cumulative}"#,
+                    self.name
+                );
+            }
+            let _ = writeln!(
+                out,
+                r#"{}_bucket{{{labels}{separator}le="+Inf"}} {}"#,
+                self.name, distribution.count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{} {}",
+                self.name,
+                braces(labels),
+                distribution.sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{} {}",
+                self.name,
+                braces(labels),
+                distribution.count
+            );
+        }
+    }
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_histograms() {
+        let histogram = Histogram::new("test_seconds", "A test.", &[0.1, 1.0]);
+        histogram.observe(r#"route="a""#, 0.05);
+        histogram.observe(r#"route="a""#, 0.5);
+        histogram.observe(r#"route="a""#, 5.0);
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert_eq!(
+            out,
+            r#"# HELP test_seconds A test.
+# TYPE test_seconds histogram
+test_seconds_bucket{route="a",le="0.1"} 1
+test_seconds_bucket{route="a",le="1"} 2
+test_seconds_bucket{route="a",le="+Inf"} 3
+test_seconds_sum{route="a"} 5.55
+test_seconds_count{route="a"} 3
+"#
+        );
+    }
+
+    #[test]
+    fn renders_counters() {
+        let counter = Counter::new("test_total", "A test.");
+        counter.add(r#"result="hit""#, 2);
+        counter.add(r#"result="hit""#, 1);
+        counter.add("", 1);
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert!(out.ends_with("test_total 1\ntest_total{result=\"hit\"} 3\n"));
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::HttpRequest;
+
+/// The amount of clients to track before forgetting those that are no longer limited.
+const CLEANUP_THRESHOLD: usize = 10_000;
+
+/// A per-client token bucket rate limiter.
+/// Each client can make a burst of up to `per_minute` requests, refilling at the same rate.
+pub struct RateLimiter {
+    capacity: f64,
+    /// Tokens refilled per second.
+    refill_rate: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            capacity: per_minute as f64,
+            refill_rate: per_minute as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from a client's bucket.
+    /// Will return how long the client has to wait if their bucket is empty.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        self.check_at(normalize(client), Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= CLEANUP_THRESHOLD {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.capacity);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        if self.refill(bucket, now) >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.refill_rate,
+            ))
+        }
+    }
+
+    /// Refills a bucket for the time passed since it was last updated, returning its tokens.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_rate).min(self.capacity);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+/// Gets the IP address of the client that made a request.
+/// The `Forwarded` and `X-Forwarded-For` headers are only used if the instance trusts them.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    if crate::args().trust_proxy_headers {
+        let info = req.connection_info();
+        let addr = info.realip_remote_addr()?;
+        addr.parse::<SocketAddr>()
+            .map(|addr| addr.ip())
+            .or_else(|_| addr.trim_matches(['[', ']']).parse())
+            .ok()
+    } else {
+        req.peer_addr().map(|addr| addr.ip())
+    }
+}
+
+/// Groups IPv6 clients by their /64 prefix, since that's typically what's assigned to a single client.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => {
+                let [a, b, c, d, ..] = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+            }
+        },
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_bursts_and_refills() {
+        let limiter = RateLimiter::new(60);
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let start = Instant::now();
+
+        for _ in 0..60 {
+            assert!(limiter.check_at(client, start).is_ok());
+        }
+        let wait = limiter.check_at(client, start).unwrap_err();
+        assert_eq!(wait.as_secs(), 1);
+        // Other clients have their own bucket.
+        assert!(
+            limiter
+                .check_at(IpAddr::from([127, 0, 0, 2]), start)
+                .is_ok()
+        );
+
+        assert!(
+            limiter
+                .check_at(client, start + Duration::from_secs(1))
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .check_at(client, start + Duration::from_secs(1))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn groups_ipv6_by_prefix() {
+        let a: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:ffff::1".parse().unwrap();
+        assert_eq!(normalize(a), normalize(b));
+        assert_eq!(
+            normalize("::ffff:10.0.0.1".parse().unwrap()),
+            IpAddr::from([10, 0, 0, 1])
+        );
+    }
+}
@@ -1,7 +1,72 @@
+use std::{collections::HashMap, fs, io, path::Path, sync::OnceLock};
+
 use actix_web::{HttpResponse, Responder, get, web};
 use include_dir::{Dir, include_dir};
 
+use crate::settings;
+
 const STATIC_RESOURCES: Dir = include_dir!("$CARGO_MANIFEST_DIR/static");
+/// Files from the instance's static override directory, by their path relative to it.
+static OVERRIDES: OnceLock<HashMap<String, Vec<u8>>> = OnceLock::new();
+
+/// The list of themes, which instances can extend with their own.
+pub const THEMES_PATH: &str = "style/theme/themes.json";
+/// An HTML snippet shown on the home page.
+const HOME_SNIPPET_PATH: &str = "home.html";
+/// Logos shown next to the site name, in order of preference.
+const LOGO_PATHS: [&str; 2] = ["logo.svg", "logo.png"];
+
+/// Loads the files in the instance's static override directory, if it has one.
+pub fn init() -> io::Result<()> {
+    let mut files = HashMap::new();
+    if let Some(dir) = &crate::args().static_override_dir {
+        load_dir(Path::new(dir), Path::new(dir), &mut files)?;
+        if let Some(themes) = files.get(THEMES_PATH) {
+            settings::check_themes(themes).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid {THEMES_PATH}: {err}"),
+                )
+            })?;
+        }
+    }
+    let _ = OVERRIDES.set(files);
+    Ok(())
+}
+
+fn load_dir(root: &Path, dir: &Path, files: &mut HashMap<String, Vec<u8>>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            load_dir(root, &path, files)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            let key = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.insert(key, fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Gets a file from the instance's static override directory.
+pub fn override_file(path: &str) -> Option<&'static [u8]> {
+    OVERRIDES.get()?.get(path).map(Vec::as_slice)
+}
+
+/// Gets the HTML snippet the instance shows on its home page.
+pub fn home_snippet() -> Option<&'static str> {
+    override_file(HOME_SNIPPET_PATH).and_then(|html| std::str::from_utf8(html).ok())
+}
+
+/// Gets the path of the instance's logo.
+pub fn logo() -> Option<&'static str> {
+    LOGO_PATHS
+        .into_iter()
+        .find(|path| override_file(path).is_some())
+}
 
 #[get("{filename:.*}")]
 pub async fn resource(path: web::Path<String>) -> impl Responder {
@@ -9,20 +74,26 @@ pub async fn resource(path: web::Path<String>) -> impl Responder {
 }
 
 fn asset(path: &str) -> impl Responder + use<> {
-    let file = match STATIC_RESOURCES.get_file(path) {
-        Some(file) => file,
-        None => return HttpResponse::NotFound().finish(),
+    // Overrides can change without the version changing, so they can't be cached forever.
+    let (contents, cache_control) = match override_file(path) {
+        Some(contents) => (contents, "public, max-age=86400"),
+        None => match STATIC_RESOURCES.get_file(path) {
+            Some(file) => (file.contents(), "public, max-age=31536000, immutable"),
+            None => return HttpResponse::NotFound().finish(),
+        },
     };
     HttpResponse::Ok()
         .append_header(("Content-Type", content_type(path)))
-        .append_header(("Cache-Control", "public, max-age=31536000, immutable"))
-        .body(file.contents())
+        .append_header(("Cache-Control", cache_control))
+        .body(contents)
 }
 
 fn content_type(path: &str) -> &str {
     match path.split('.').next_back().unwrap_or_default() {
         "css" => "text/css",
         "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
         "woff2" => "font/woff2",
         "json" => "application/json",
         _ => "text/plain",
@@ -0,0 +1,294 @@
+use std::{borrow::Cow, collections::HashSet, sync::LazyLock};
+
+use crate::api::{self, PLACEHOLDER_SIZE, SMALL_SIZE};
+use crate::frontends::Frontends;
+use crate::settings::{DataSaver, GifMode, Settings};
+use ammonia::Builder;
+use lazy_regex::*;
+use regex::{Captures, Regex};
+
+static GENIUS_IMAGE_URL: &str = "https://images.genius.com/";
+static GENIUS_IMAGE_ALT_URL: &str = "https://images.rapgenius.com/";
+static GENIUS_BASE_PATTERN: Lazy<Regex> = lazy_regex!(r#"^https?://(\w+\.)?genius\.com"#);
+static PROXIED_IMAGE_PATTERN: Lazy<Regex> =
+    lazy_regex!(r#"(<a [^>]*>\s*)?<img ([^>]*?)src="(/api/image\?url=[^"]*)"([^>]*)>"#);
+static PROXIED_GIF_PATTERN: Lazy<Regex> = lazy_regex!(
+    r#"(<a [^>]*>\s*)?<img ([^>]*?)src="(/api/image\?url=[^"&]*?\.gif(?:&amp;[^"]*)?)"([^>]*)>"#
+);
+
+static LOCAL_LINK_PATTERN: Lazy<Regex> = lazy_regex!(r##"href="(/[^"#]*)(#[^"]*)?""##);
+
+static SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::default();
+    builder
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("img", "src") => proxy_image(value).map(Cow::Owned),
+            (_, "href") => Some(Cow::Owned(rewrite_link(value))),
+            _ => Some(Cow::Borrowed(value)),
+        });
+    builder
+});
+
+/// Sanitizes HTML from Genius so it can be safely rendered on our pages.
+///
+/// Only an allowlist of tags, attributes and URL schemes are kept.
+/// Images are proxied, and dropped entirely if they aren't hosted by Genius.
+/// Links to Genius are made relative since we follow their schema,
+/// and links to other services are redirected to the instance's frontends.
+pub fn sanitize(html: &str) -> String {
+    SANITIZER.clean(html).to_string()
+}
+
+/// Replaces proxied GIFs in sanitized HTML with their first frame, linking to the animated GIF.
+/// GIFs that are already linked keep their link.
+pub fn still_gifs(html: &str) -> String {
+    PROXIED_GIF_PATTERN
+        .replace_all(html, |caps: &Captures| {
+            let src = &caps[3];
+            let img = format!(
+                r#"<img {}src="{src}&amp;still=true"{}>"#,
+                &caps[2], &caps[4]
+            );
+            match caps.get(1) {
+                Some(link) => format!("{}{img}", link.as_str()),
+                None => format!(r#"<a class="still-gif" href="{src}">{img}</a>"#),
+            }
+        })
+        .into_owned()
+}
+
+/// Makes proxied GIFs in sanitized HTML load as animated WebP.
+pub fn webp_gifs(html: &str) -> String {
+    PROXIED_GIF_PATTERN
+        .replace_all(html, |caps: &Captures| {
+            format!(
+                r#"{}<img {}src="{}&amp;webp=true"{}>"#,
+                caps.get(1).map_or("", |link| link.as_str()),
+                &caps[2],
+                &caps[3],
+                &caps[4]
+            )
+        })
+        .into_owned()
+}
+
+/// Applies a user's settings to sanitized HTML, i.e. their frontends and image preferences.
+pub fn personalize(html: &str, settings: &Settings, frontends: &Frontends) -> String {
+    let mut html = if settings.has_frontends() {
+        frontends.rewrite_html(html)
+    } else {
+        html.to_owned()
+    };
+    if let Some(data_saver) = settings.data_saver() {
+        html = save_data(&html, data_saver);
+    }
+    match settings.gif_mode() {
+        GifMode::Still => html = still_gifs(&html),
+        GifMode::WebP => html = webp_gifs(&html),
+        GifMode::Animate => {}
+    }
+    if settings.from_url {
+        html = keep_url_settings(&html, &settings.encode());
+    }
+    html
+}
+
+/// Adds encoded settings to links to other pages on the instance in sanitized HTML,
+/// so users browsing without cookies keep their settings.
+pub fn keep_url_settings(html: &str, encoded: &str) -> String {
+    LOCAL_LINK_PATTERN
+        .replace_all(html, |caps: &Captures| {
+            let path = &caps[1];
+            if path.starts_with("/api/") {
+                return caps[0].to_owned();
+            }
+            let separator = if path.contains('?') { "&amp;" } else { "?" };
+            let fragment = caps.get(2).map_or("", |m| m.as_str());
+            format!(r#"href="{path}{separator}s={encoded}{fragment}""#)
+        })
+        .into_owned()
+}
+
+/// Hides or shrinks proxied images in sanitized HTML, linking to the full image.
+/// Images that are already linked keep their link.
+pub fn save_data(html: &str, data_saver: DataSaver) -> String {
+    PROXIED_IMAGE_PATTERN
+        .replace_all(html, |caps: &Captures| {
+            let src = &caps[3];
+            let content = match data_saver {
+                DataSaver::Hide => "Show image".to_owned(),
+                DataSaver::Placeholder => format!(
+                    r#"<img class="placeholder-image" {}src="{src}&amp;size={PLACEHOLDER_SIZE}"{}>"#,
+                    &caps[2], &caps[4]
+                ),
+                DataSaver::Small => format!(
+                    r#"<img {}src="{src}&amp;size={SMALL_SIZE}"{}>"#,
+                    &caps[2], &caps[4]
+                ),
+            };
+            match caps.get(1) {
+                Some(link) => format!("{}{content}", link.as_str()),
+                None => format!(r#"<a class="data-saver-image" href="{src}">{content}</a>"#),
+            }
+        })
+        .into_owned()
+}
+
+fn proxy_image(src: &str) -> Option<String> {
+    let src = src.replace(GENIUS_IMAGE_ALT_URL, GENIUS_IMAGE_URL);
+    src.starts_with(GENIUS_IMAGE_URL)
+        .then(|| api::image_url(&src))
+}
+
+fn rewrite_link(href: &str) -> String {
+    if let Some(proxied) = proxy_image(href) {
+        return proxied;
+    }
+    if GENIUS_BASE_PATTERN.is_match(href) {
+        let path = GENIUS_BASE_PATTERN.replace(href, "");
+        return if path.is_empty() {
+            "/".into()
+        } else {
+            path.into_owned()
+        };
+    }
+    Frontends::instance().redirect(href)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_scripts_and_embeds() {
+        assert_eq!(sanitize("<p>Hi<script>alert(1)</script></p>"), "<p>Hi</p>");
+        assert_eq!(
+            sanitize(r#"<iframe src="https://www.youtube.com/embed/id"></iframe>"#),
+            ""
+        );
+        assert_eq!(sanitize("<style>body { display: none }</style>"), "");
+        assert_eq!(
+            sanitize(r#"<object data="x.swf"></object><embed src="x.swf">"#),
+            ""
+        );
+        assert_eq!(sanitize(r#"<svg onload="alert(1)"><circle/></svg>"#), "");
+    }
+
+    #[test]
+    fn strips_event_handlers_and_styles() {
+        assert_eq!(
+            sanitize(r#"<p onclick="alert(1)" style="position: fixed">Hi</p>"#),
+            "<p>Hi</p>"
+        );
+        assert_eq!(
+            sanitize(r#"<img src="https://images.genius.com/a.png" onerror="alert(1)">"#),
+            r#"<img src="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.png">"#
+        );
+    }
+
+    #[test]
+    fn strips_unsafe_urls() {
+        assert_eq!(
+            sanitize(r#"<a href="javascript:alert(1)">Hi</a>"#),
+            r#"<a rel="noopener noreferrer">Hi</a>"#
+        );
+        assert_eq!(
+            sanitize(r#"<a href="data:text/html,<script>alert(1)</script>">Hi</a>"#),
+            r#"<a rel="noopener noreferrer">Hi</a>"#
+        );
+        assert_eq!(
+            sanitize(r#"<a href="JaVaScRiPt&colon;alert(1)">Hi</a>"#),
+            r#"<a rel="noopener noreferrer">Hi</a>"#
+        );
+    }
+
+    #[test]
+    fn drops_third_party_images() {
+        assert_eq!(
+            sanitize(r#"<img src="https://tracker.example/pixel.gif">"#),
+            "<img>"
+        );
+        assert_eq!(
+            sanitize(r#"<img src="https://images.rapgenius.com/a.gif">"#),
+            r#"<img src="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.gif">"#
+        );
+    }
+
+    #[test]
+    fn still_gifs_link_to_animation() {
+        let html = sanitize(r#"<p><img src="https://images.genius.com/a.gif" alt="A"></p>"#);
+        assert_eq!(
+            still_gifs(&html),
+            r#"<p><a class="still-gif" href="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.gif"><img src="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.gif&amp;still=true" alt="A"></a></p>"#
+        );
+
+        let html = sanitize(
+            r#"<a href="https://images.genius.com/a.gif"><img src="https://images.genius.com/a.gif"></a>"#,
+        );
+        assert_eq!(
+            still_gifs(&html),
+            r#"<a href="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.gif" rel="noopener noreferrer"><img src="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.gif&amp;still=true"></a>"#
+        );
+
+        let html = sanitize(r#"<img src="https://images.genius.com/a.png">"#);
+        assert_eq!(still_gifs(&html), html);
+    }
+
+    #[test]
+    fn webp_gifs_keep_links() {
+        let html = sanitize(
+            r#"<a href="https://genius.com/a"><img src="https://images.genius.com/a.gif"></a>"#,
+        );
+        assert_eq!(
+            webp_gifs(&html),
+            r#"<a href="/a" rel="noopener noreferrer"><img src="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.gif&amp;webp=true"></a>"#
+        );
+    }
+
+    #[test]
+    fn data_saver_links_to_full_images() {
+        let html = sanitize(r#"<img src="https://images.genius.com/a.png">"#);
+        assert_eq!(
+            save_data(&html, DataSaver::Hide),
+            r#"<a class="data-saver-image" href="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.png">Show image</a>"#
+        );
+        assert_eq!(
+            save_data(&html, DataSaver::Small),
+            r#"<a class="data-saver-image" href="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.png"><img src="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.png&amp;size=150"></a>"#
+        );
+
+        let html = sanitize(
+            r#"<a href="https://example.com/"><img src="https://images.genius.com/a.png"></a>"#,
+        );
+        assert_eq!(
+            save_data(&html, DataSaver::Placeholder),
+            r#"<a href="https://example.com/" rel="noopener noreferrer"><img class="placeholder-image" src="/api/image?url=https%3A%2F%2Fimages.genius.com%2Fa.png&amp;size=32"></a>"#
+        );
+    }
+
+    #[test]
+    fn local_links_keep_url_settings() {
+        assert_eq!(
+            keep_url_settings(
+                r#"<a href="/artists/Someone">A</a> <a href="/search?q=a&amp;page=2#top">B</a>"#,
+                "abc"
+            ),
+            r#"<a href="/artists/Someone?s=abc">A</a> <a href="/search?q=a&amp;page=2&amp;s=abc#top">B</a>"#
+        );
+        let external = r#"<a href="https://example.com/">A</a> <a href="/api/image?url=a">B</a>"#;
+        assert_eq!(keep_url_settings(external, "abc"), external);
+    }
+
+    #[test]
+    fn rewrites_genius_links() {
+        assert_eq!(
+            sanitize(r#"<a href="https://genius.com/artists/Someone">Someone</a>"#),
+            r#"<a href="/artists/Someone" rel="noopener noreferrer">Someone</a>"#
+        );
+        assert_eq!(
+            sanitize(r#"<a href="https://example.com/">Hi</a>"#),
+            r#"<a href="https://example.com/" rel="noopener noreferrer">Hi</a>"#
+        );
+    }
+}
@@ -6,7 +6,7 @@ use askama::Template;
 use serde::Deserialize;
 
 use crate::genius::{self, GeniusSong};
-use crate::settings::{Settings, settings_from_req};
+use crate::settings::{SearchType, Settings, settings_from_req};
 use crate::templates::template;
 use crate::utils;
 
@@ -20,29 +20,48 @@ struct SearchTemplate {
     q: String,
     current_page: u8,
     nav_pages: Vec<u8>,
+    search_type: SearchType,
+    search_types: &'static [SearchType],
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SearchQuery {
     q: String,
     page: Option<u8>,
+    /// What to search for. Defaults to the user's preference.
+    #[serde(rename = "type")]
+    search_type: Option<String>,
 }
 
 #[get("/search")]
 pub async fn search(req: HttpRequest, info: web::Query<SearchQuery>) -> Result<impl Responder> {
     let current_page = info.page.unwrap_or(1);
+    let settings = settings_from_req(&req);
+    let search_type = info
+        .search_type
+        .as_deref()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or_else(|| settings.default_search());
 
-    let songs = genius::get_search_results(&info.q, current_page).await?;
+    let songs = match search_type {
+        SearchType::Songs => genius::get_search_results(&info.q, current_page).await?,
+        SearchType::Lyrics => genius::get_lyric_search_results(&info.q, current_page).await?,
+    };
 
     let nav_min = max(1, current_page.saturating_sub(NAV_PAGE_COUNT));
     let nav_max = min(100, current_page.saturating_add(NAV_PAGE_COUNT));
     let nav_pages = RangeInclusive::new(nav_min, nav_max).collect();
 
-    Ok(template(SearchTemplate {
-        settings: settings_from_req(&req),
-        q: info.q.to_owned(),
-        current_page,
-        nav_pages,
-        songs,
-    }))
+    Ok(template(
+        &req,
+        SearchTemplate {
+            settings,
+            q: info.q.to_owned(),
+            current_page,
+            nav_pages,
+            songs,
+            search_type,
+            search_types: SearchType::ALL,
+        },
+    ))
 }
@@ -1,77 +1,1029 @@
-use std::sync::LazyLock;
+use std::{mem, str::FromStr, sync::LazyLock};
 
-use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web::Form};
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, Responder, error, get, post,
+    web::{Form, Query},
+};
 use askama::Template;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use cookie::Cookie;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
+use crate::frontends::{self, Service};
+use crate::resource;
 use crate::templates::template;
 
 pub const SETTINGS_KEY: &str = "settings";
+/// The version of the stored settings format.
+/// Bump this and add a migration when a setting is renamed or its values change.
+const SETTINGS_VERSION: u32 = 1;
+/// The ID of the theme made from the user's own colors.
+pub const CUSTOM_THEME: &str = "custom";
 
 static THEME_CONFIG: LazyLock<ThemeConfig> = LazyLock::new(|| {
-    serde_json::from_str(include_str!("../static/style/theme/themes.json")).unwrap()
+    // Themes added by the instance are checked when the overrides are loaded, so they're valid here.
+    load_themes(resource::override_file(resource::THEMES_PATH))
+        .unwrap_or_else(|_| load_themes(None).unwrap())
 });
 
 #[derive(Template)]
 #[template(path = "settings.html")]
 struct SettingsTemplate {
     settings: Settings,
+    /// A link that restores the current settings.
+    restore_url: String,
     themes: Vec<Theme>,
+    theme_pairs: Vec<ThemePair>,
+    services: [Service; 6],
+    gif_modes: &'static [GifMode],
+    data_savers: &'static [DataSaver],
+    font_sizes: &'static [FontSize],
+    line_spacings: &'static [LineSpacing],
+    lyric_alignments: &'static [LyricAlignment],
+    annotation_displays: &'static [AnnotationDisplay],
+    search_types: &'static [SearchType],
+    color_schemes: &'static [ColorScheme],
 }
 
 #[get("/settings")]
 pub async fn settings(req: HttpRequest) -> impl Responder {
-    template(SettingsTemplate {
-        settings: settings_from_req(&req),
-        themes: THEME_CONFIG.themes.clone(),
-    })
+    let settings = settings_from_req(&req);
+    let conn = req.connection_info();
+    let restore_url = format!(
+        "{}://{}/settings/restore?code={}",
+        conn.scheme(),
+        conn.host(),
+        settings.encode()
+    );
+    template(
+        &req,
+        SettingsTemplate {
+            restore_url,
+            settings,
+            themes: THEME_CONFIG.themes.clone(),
+            theme_pairs: THEME_CONFIG.pairs.clone(),
+            services: Service::ALL,
+            gif_modes: GifMode::ALL,
+            data_savers: DataSaver::ALL,
+            font_sizes: FontSize::ALL,
+            line_spacings: LineSpacing::ALL,
+            lyric_alignments: LyricAlignment::ALL,
+            annotation_displays: AnnotationDisplay::ALL,
+            search_types: SearchType::ALL,
+            color_schemes: ColorScheme::ALL,
+        },
+    )
 }
 
 #[post("/settings")]
-pub async fn settings_form(form: Form<Settings>) -> impl Responder {
-    match serde_json::to_string(&form) {
+pub async fn settings_form(req: HttpRequest, form: Form<Settings>) -> impl Responder {
+    if !has_url_settings(&req) {
+        return save(&form);
+    }
+    // Settings are kept in the URL, so keep them there instead of setting a cookie.
+    if !form.is_valid() {
+        return HttpResponse::BadRequest().finish();
+    }
+    HttpResponse::SeeOther()
+        .append_header(("Location", format!("/settings?s={}", form.encode())))
+        .finish()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SettingsQuery {
+    /// Settings encoded with [`Settings::encode`].
+    s: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestoreQuery {
+    /// Settings encoded with [`Settings::encode`]. This isn't `s`, since the page itself
+    /// is shown with the user's own settings.
+    code: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "restore.html")]
+struct RestoreTemplate {
+    settings: Settings,
+    /// The settings to restore, as encoded by [`Settings::encode`].
+    encoded: String,
+    /// The settings that will be changed from their default.
+    changes: Vec<(String, String)>,
+}
+
+/// Shows settings exported from the settings page, asking the user to confirm restoring them.
+/// Restoring them is a separate POST, so links and embeds can't change a user's settings.
+#[get("/settings/restore")]
+pub async fn restore(req: HttpRequest, info: Query<RestoreQuery>) -> impl Responder {
+    let Some(encoded) = info.code.as_deref() else {
+        return invalid_settings_code();
+    };
+    match Settings::decode(encoded) {
+        Some(restored) => template(
+            &req,
+            RestoreTemplate {
+                settings: settings_from_req(&req),
+                encoded: restored.encode(),
+                changes: restored.changes(),
+            },
+        ),
+        None => invalid_settings_code(),
+    }
+}
+
+/// Restores settings exported from the settings page, once the user confirmed it.
+#[post("/settings/restore")]
+pub async fn restore_form(form: Form<RestoreQuery>) -> impl Responder {
+    match form.code.as_deref().and_then(Settings::decode) {
+        Some(restored) => save(&restored),
+        None => invalid_settings_code(),
+    }
+}
+
+fn invalid_settings_code() -> HttpResponse {
+    HttpResponse::from_error(error::ErrorBadRequest("Invalid settings code."))
+}
+
+/// Saves settings to the user's cookie and sends them back to the settings page.
+fn save(new: &Settings) -> HttpResponse {
+    if !new.is_valid() {
+        return HttpResponse::BadRequest().finish();
+    }
+    match serde_json::to_string(new) {
         Ok(str) => HttpResponse::SeeOther()
-            .cookie(Cookie::new(SETTINGS_KEY, str))
+            .cookie(Cookie::build(SETTINGS_KEY, str).path("/").finish())
             .append_header(("Location", "/settings"))
             .finish(),
         Err(_) => HttpResponse::BadRequest().finish(),
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Defines a setting with a fixed set of choices, which are stored by their ID.
+macro_rules! choices {
+    ($(#[$meta:meta])* pub enum $name:ident ($description:literal) {
+        $($(#[$variant_meta:meta])* $variant:ident => ($id:literal, $label:literal)),+ $(,)?
+    }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant),+
+        }
+
+        impl $name {
+            pub const ALL: &'static [$name] = &[$($name::$variant),+];
+
+            pub fn id(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $id),+
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $label),+
+                }
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.id())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Self::ALL
+                    .iter()
+                    .copied()
+                    .find(|choice| choice.id() == s)
+                    .ok_or_else(|| format!("Unknown {} '{s}'", $description))
+            }
+        }
+    };
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+// Fields missing from older cookies fall back to their defaults instead of resetting everything.
+#[serde(default)]
 pub struct Settings {
+    /// The version of the format the settings were stored in.
+    pub version: u32,
     pub theme: String,
+    pub color_performers: bool,
+    pub embed_media: bool,
+    /// How animated GIFs are shown. Empty to use the instance's default.
+    pub gif_mode: String,
+    /// How images are limited to save data. Empty to show images normally.
+    pub data_saver: String,
+    // Lyric layout. Empty to use the default.
+    pub font_size: String,
+    pub line_spacing: String,
+    pub lyric_alignment: String,
+    /// Annotated lyrics are highlighted unless this is set.
+    pub hide_annotation_highlights: bool,
+    /// How annotations are shown. Empty to show them in a pop-up.
+    pub annotation_display: String,
+    pub hide_pageviews: bool,
+    /// What's searched for by default. Empty to search for songs.
+    pub default_search: String,
+    // Frontends to redirect links to. Empty to use the instance's default.
+    pub youtube_frontend: String,
+    pub twitter_frontend: String,
+    pub reddit_frontend: String,
+    pub instagram_frontend: String,
+    pub tiktok_frontend: String,
+    pub wikipedia_frontend: String,
+    // Colors of the custom theme, as hex colors. These default to the default theme's colors.
+    pub custom_color_scheme: String,
+    pub custom_background: String,
+    pub custom_foreground: String,
+    pub custom_highlighted: String,
+    pub custom_text: String,
+    pub custom_text_dark: String,
+    pub custom_text_highlight: String,
+    pub custom_yellow: String,
+    pub custom_red: String,
+    /// Whether the settings were read from the URL instead of the cookie.
+    /// If they were, links to other pages carry them along.
+    #[serde(skip)]
+    pub from_url: bool,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
-            theme: "github-dark".into(),
+            version: SETTINGS_VERSION,
+            theme: "github".into(),
+            color_performers: false,
+            embed_media: false,
+            gif_mode: String::new(),
+            data_saver: String::new(),
+            font_size: String::new(),
+            line_spacing: String::new(),
+            lyric_alignment: String::new(),
+            hide_annotation_highlights: false,
+            annotation_display: String::new(),
+            hide_pageviews: false,
+            default_search: String::new(),
+            youtube_frontend: String::new(),
+            twitter_frontend: String::new(),
+            reddit_frontend: String::new(),
+            instagram_frontend: String::new(),
+            tiktok_frontend: String::new(),
+            wikipedia_frontend: String::new(),
+            custom_color_scheme: String::new(),
+            custom_background: "#0d1117".into(),
+            custom_foreground: "#161b22".into(),
+            custom_highlighted: "#1b2027".into(),
+            custom_text: "#c9d1d9".into(),
+            custom_text_dark: "#85817b".into(),
+            custom_text_highlight: "#25292e".into(),
+            custom_yellow: "#ffff64".into(),
+            custom_red: "#da3633".into(),
+            from_url: false,
         }
     }
 }
 
 impl Settings {
+    /// Parses settings stored as JSON, migrating them from older versions.
+    /// Values that are missing or invalid fall back to their default without affecting the others.
+    pub fn from_json(json: &str) -> Self {
+        let Ok(Value::Object(mut fields)) = serde_json::from_str(json) else {
+            return Self::default();
+        };
+        migrate(&mut fields);
+
+        let Ok(Value::Object(defaults)) = serde_json::to_value(Self::default()) else {
+            return Self::default();
+        };
+        let mut merged = defaults.clone();
+        for (key, value) in fields {
+            // Settings that have since been removed, and values of the wrong type, are dropped.
+            // All settings are strings, booleans or numbers, so this ensures they deserialize.
+            if merged
+                .get(&key)
+                .is_some_and(|default| mem::discriminant(default) == mem::discriminant(&value))
+            {
+                merged.insert(key, value);
+            }
+        }
+        let Ok(mut parsed) = serde_json::from_value::<Self>(Value::Object(merged.clone())) else {
+            return Self::default();
+        };
+
+        let invalid: Vec<String> = merged
+            .keys()
+            .filter(|key| !parsed.is_field_valid(key))
+            .cloned()
+            .collect();
+        if !invalid.is_empty() {
+            for key in invalid {
+                merged[&key] = defaults[&key].clone();
+            }
+            parsed = serde_json::from_value(Value::Object(merged)).unwrap_or_default();
+        }
+        parsed
+    }
+
+    /// Encodes the settings into a compact, URL-safe string.
+    /// Only settings that differ from their default are included.
+    pub fn encode(&self) -> String {
+        let mut fields = self.changed_fields();
+        fields.insert("version".into(), self.version.into());
+        URL_SAFE_NO_PAD.encode(Value::Object(fields).to_string())
+    }
+
+    /// Resets the settings that send users to or load content from other sites, i.e. frontends and
+    /// media players. Anyone can link to a page with settings in its URL, so they can't be trusted.
+    fn without_external(self) -> Self {
+        let defaults = Self::default();
+        Settings {
+            embed_media: defaults.embed_media,
+            youtube_frontend: defaults.youtube_frontend,
+            twitter_frontend: defaults.twitter_frontend,
+            reddit_frontend: defaults.reddit_frontend,
+            instagram_frontend: defaults.instagram_frontend,
+            tiktok_frontend: defaults.tiktok_frontend,
+            wikipedia_frontend: defaults.wikipedia_frontend,
+            ..self
+        }
+    }
+
+    /// Gets the settings that differ from their default, as their name and value.
+    pub fn changes(&self) -> Vec<(String, String)> {
+        self.changed_fields()
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(str) => str,
+                    value => value.to_string(),
+                };
+                (key.replace('_', " "), value)
+            })
+            .collect()
+    }
+
+    fn changed_fields(&self) -> Map<String, Value> {
+        let (Ok(Value::Object(mut fields)), Ok(Value::Object(defaults))) = (
+            serde_json::to_value(self),
+            serde_json::to_value(Self::default()),
+        ) else {
+            return Map::new();
+        };
+        fields.retain(|key, value| key != "version" && defaults.get(key) != Some(value));
+        fields
+    }
+
+    /// Gets the query parameter that carries settings read from the URL to other pages,
+    /// starting with `separator`. Empty if the settings came from the cookie.
+    pub fn query(&self, separator: char) -> String {
+        if self.from_url {
+            format!("{separator}s={}", self.encode())
+        } else {
+            String::new()
+        }
+    }
+
+    /// Decodes settings encoded with [`Settings::encode`].
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(encoded.trim()).ok()?;
+        Some(Self::from_json(std::str::from_utf8(&json).ok()?))
+    }
+
     pub fn is_valid(&self) -> bool {
-        THEME_CONFIG.themes.iter().any(|t| t.id == self.theme)
+        match serde_json::to_value(self) {
+            Ok(Value::Object(fields)) => fields.keys().all(|key| self.is_field_valid(key)),
+            _ => false,
+        }
+    }
+
+    /// Whether the value of a single setting is valid.
+    fn is_field_valid(&self, key: &str) -> bool {
+        match key {
+            "version" => self.version == SETTINGS_VERSION,
+            "theme" => {
+                self.theme == CUSTOM_THEME
+                    || THEME_CONFIG.themes.iter().any(|t| t.id == self.theme)
+                    || THEME_CONFIG.pairs.iter().any(|p| p.id == self.theme)
+            }
+            "custom_color_scheme" => is_choice::<ColorScheme>(&self.custom_color_scheme),
+            "gif_mode" => is_choice::<GifMode>(&self.gif_mode),
+            "data_saver" => is_choice::<DataSaver>(&self.data_saver),
+            "font_size" => is_choice::<FontSize>(&self.font_size),
+            "line_spacing" => is_choice::<LineSpacing>(&self.line_spacing),
+            "lyric_alignment" => is_choice::<LyricAlignment>(&self.lyric_alignment),
+            "annotation_display" => is_choice::<AnnotationDisplay>(&self.annotation_display),
+            "default_search" => is_choice::<SearchType>(&self.default_search),
+            _ if key.starts_with("custom_") => self
+                .custom_colors()
+                .iter()
+                .filter(|color| color.field == key)
+                .all(|color| is_color(color.value)),
+            _ => match Service::ALL
+                .into_iter()
+                .find(|service| key.strip_suffix("_frontend") == Some(service.id()))
+            {
+                Some(service) => {
+                    let url = self.frontend(&service);
+                    url.is_empty() || frontends::is_valid_url(url)
+                }
+                None => true,
+            },
+        }
+    }
+
+    /// Gets the stylesheets of the user's theme.
+    /// Theme pairs have both of their themes, with the dark theme only applying if the system prefers it.
+    pub fn theme_stylesheets(&self) -> Vec<ThemeStylesheet> {
+        match THEME_CONFIG.pairs.iter().find(|p| p.id == self.theme) {
+            // The light theme applies unconditionally, so there's a theme if the system has no preference.
+            Some(pair) => vec![
+                ThemeStylesheet {
+                    theme: pair.light.clone(),
+                    media: None,
+                },
+                ThemeStylesheet {
+                    theme: pair.dark.clone(),
+                    media: Some("(prefers-color-scheme: dark)"),
+                },
+            ],
+            None => vec![ThemeStylesheet {
+                theme: self.theme.clone(),
+                media: None,
+            }],
+        }
+    }
+
+    /// Gets the colors of the custom theme.
+    pub fn custom_colors(&self) -> [ThemeColor<'_>; 8] {
+        [
+            ThemeColor::new("custom_background", "Background", &self.custom_background),
+            ThemeColor::new("custom_foreground", "Foreground", &self.custom_foreground),
+            ThemeColor::new("custom_highlighted", "Highlight", &self.custom_highlighted),
+            ThemeColor::new("custom_text", "Text", &self.custom_text),
+            ThemeColor::new("custom_text_dark", "Secondary text", &self.custom_text_dark),
+            ThemeColor::new(
+                "custom_text_highlight",
+                "Annotation highlight",
+                &self.custom_text_highlight,
+            ),
+            ThemeColor::new("custom_yellow", "Accent", &self.custom_yellow),
+            ThemeColor::new("custom_red", "Warning", &self.custom_red),
+        ]
+    }
+
+    /// Gets the stylesheet of the custom theme, if the user chose it.
+    pub fn custom_theme_css(&self) -> Option<String> {
+        if self.theme != CUSTOM_THEME {
+            return None;
+        }
+        let color_scheme = self
+            .custom_color_scheme
+            .parse()
+            .unwrap_or(ColorScheme::Dark)
+            .id();
+        let mut css = format!(":root{{color-scheme:{color_scheme};");
+        for color in self.custom_colors() {
+            // Colors are validated, so they can't escape the style block.
+            if is_color(color.value) {
+                css.push_str(&format!("{}:{};", color.variable(), color.value));
+            }
+        }
+        css.push('}');
+        Some(css)
+    }
+
+    /// Gets the frontend the user chose for a service, or an empty string for the instance's default.
+    pub fn frontend(&self, service: &Service) -> &str {
+        match service {
+            Service::YouTube => &self.youtube_frontend,
+            Service::Twitter => &self.twitter_frontend,
+            Service::Reddit => &self.reddit_frontend,
+            Service::Instagram => &self.instagram_frontend,
+            Service::TikTok => &self.tiktok_frontend,
+            Service::Wikipedia => &self.wikipedia_frontend,
+        }
+    }
+
+    /// How animated GIFs should be shown to the user.
+    pub fn gif_mode(&self) -> GifMode {
+        self.gif_mode.parse().unwrap_or(crate::args().gif_mode)
+    }
+
+    pub fn data_saver(&self) -> Option<DataSaver> {
+        self.data_saver.parse().ok()
+    }
+
+    /// Whether annotations should be expanded inline instead of in a pop-up.
+    pub fn inline_annotations(&self) -> bool {
+        self.annotation_display.parse() == Ok(AnnotationDisplay::Inline)
+    }
+
+    pub fn default_search(&self) -> SearchType {
+        self.default_search.parse().unwrap_or(SearchType::Songs)
+    }
+
+    /// The CSS classes applying the user's layout preferences, added to the page's body.
+    /// Inline styles would be blocked by our CSP.
+    pub fn body_class(&self) -> String {
+        let mut classes = Vec::new();
+        if let Ok(font_size) = self.font_size.parse::<FontSize>() {
+            classes.push(format!("font-{}", font_size.id()));
+        }
+        if let Ok(line_spacing) = self.line_spacing.parse::<LineSpacing>() {
+            classes.push(format!("lines-{}", line_spacing.id()));
+        }
+        if let Ok(alignment) = self.lyric_alignment.parse::<LyricAlignment>() {
+            classes.push(format!("lyrics-{}", alignment.id()));
+        }
+        if self.hide_annotation_highlights {
+            classes.push("no-annotation-highlights".to_owned());
+        }
+        classes.join(" ")
+    }
+
+    /// Whether the user chose any frontends of their own.
+    pub fn has_frontends(&self) -> bool {
+        Service::ALL
+            .into_iter()
+            .any(|service| !self.frontend(&service).is_empty())
     }
 }
 
+/// Whether a setting is empty, i.e. its default, or one of its choices.
+fn is_choice<T: FromStr>(value: &str) -> bool {
+    value.is_empty() || value.parse::<T>().is_ok()
+}
+
+/// Whether a value is a hex color, e.g. `#fff` or `#0d1117`.
+fn is_color(value: &str) -> bool {
+    value.strip_prefix('#').is_some_and(|hex| {
+        matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+/// A color of the custom theme.
+pub struct ThemeColor<'a> {
+    /// The setting the color is stored in.
+    pub field: &'static str,
+    pub label: &'static str,
+    pub value: &'a str,
+}
+
+impl<'a> ThemeColor<'a> {
+    fn new(field: &'static str, label: &'static str, value: &'a str) -> Self {
+        ThemeColor {
+            field,
+            label,
+            value,
+        }
+    }
+
+    /// The theme's CSS variable the color sets.
+    fn variable(&self) -> String {
+        format!(
+            "--{}",
+            self.field.trim_start_matches("custom_").replace('_', "-")
+        )
+    }
+}
+
+/// Migrates stored settings to the current version, one version at a time.
+fn migrate(fields: &mut Map<String, Value>) {
+    // Settings from before they were versioned don't have a version.
+    let mut version = fields
+        .get("version")
+        .and_then(Value::as_u64)
+        .unwrap_or_default();
+    while version < SETTINGS_VERSION as u64 {
+        match version {
+            // Unversioned settings only lack the newer fields, which fall back to their defaults.
+            0 => {}
+            // Versions we can't migrate from fall back to the defaults.
+            _ => {
+                fields.clear();
+                break;
+            }
+        }
+        version += 1;
+    }
+    fields.insert("version".into(), SETTINGS_VERSION.into());
+}
+
+/// Gets the user's settings.
+/// Settings encoded in the `s` query parameter take precedence over the settings cookie.
+/// They're only parsed once per request.
 pub fn settings_from_req(req: &HttpRequest) -> Settings {
-    req.cookie(SETTINGS_KEY)
-        .and_then(|cookie| serde_json::from_str::<Settings>(cookie.value()).ok())
-        .filter(|s| s.is_valid())
+    if let Some(parsed) = req.extensions().get::<Settings>() {
+        return parsed.clone();
+    }
+    let parsed = parse_settings(req);
+    req.extensions_mut().insert(parsed.clone());
+    parsed
+}
+
+fn parse_settings(req: &HttpRequest) -> Settings {
+    url_settings(req)
+        .map(|decoded| Settings {
+            from_url: true,
+            ..decoded
+        })
+        .or_else(|| {
+            req.cookie(SETTINGS_KEY)
+                .map(|cookie| Settings::from_json(cookie.value()))
+        })
         .unwrap_or_default()
 }
 
+/// Gets the settings encoded in the `s` query parameter, if any.
+fn url_settings(req: &HttpRequest) -> Option<Settings> {
+    Query::<SettingsQuery>::from_query(req.query_string())
+        .ok()?
+        .s
+        .as_deref()
+        .and_then(Settings::decode)
+        .map(Settings::without_external)
+}
+
+/// Whether a request's settings are read from the URL, in which case the response
+/// doesn't depend on the user's cookies.
+pub fn has_url_settings(req: &HttpRequest) -> bool {
+    settings_from_req(req).from_url
+}
+
+choices! {
+    /// How animated GIFs are shown.
+    pub enum GifMode ("GIF mode") {
+        /// GIFs are passed through as-is.
+        Animate => ("animate", "Animated"),
+        /// Only the first frame of GIFs is shown, linking to the animated GIF.
+        Still => ("still", "Still, click to animate"),
+        /// GIFs are converted to animated WebP, which is much smaller.
+        WebP => ("webp", "Converted to animated WebP"),
+    }
+}
+
+choices! {
+    /// How images are limited for users on metered connections.
+    pub enum DataSaver ("data saver mode") {
+        /// Images aren't loaded at all.
+        Hide => ("hide", "Hide images"),
+        /// Only tiny, blurred versions of images are loaded.
+        Placeholder => ("placeholder", "Blurred placeholders"),
+        /// Images are loaded at a small size.
+        Small => ("small", "Small images"),
+    }
+}
+
+choices! {
+    pub enum FontSize ("font size") {
+        Small => ("small", "Small"),
+        Large => ("large", "Large"),
+        Larger => ("larger", "Larger"),
+    }
+}
+
+choices! {
+    pub enum LineSpacing ("line spacing") {
+        Compact => ("compact", "Compact"),
+        Relaxed => ("relaxed", "Relaxed"),
+    }
+}
+
+choices! {
+    pub enum LyricAlignment ("lyric alignment") {
+        Center => ("center", "Centered"),
+    }
+}
+
+choices! {
+    pub enum AnnotationDisplay ("annotation display") {
+        /// Annotations are expanded below their lyrics.
+        Inline => ("inline", "Inline"),
+    }
+}
+
+choices! {
+    pub enum ColorScheme ("color scheme") {
+        Dark => ("dark", "Dark"),
+        Light => ("light", "Light"),
+    }
+}
+
+choices! {
+    pub enum SearchType ("search type") {
+        Songs => ("songs", "Songs"),
+        Lyrics => ("lyrics", "Lyrics"),
+    }
+}
+
 #[derive(Clone, Deserialize)]
 struct Theme {
     id: String,
     name: String,
 }
 
+/// A light and a dark theme, which are switched between based on the system's appearance.
+#[derive(Clone, Deserialize)]
+struct ThemePair {
+    id: String,
+    name: String,
+    light: String,
+    dark: String,
+}
+
+/// Loads the built-in themes, along with the themes an instance adds in its static override directory.
+/// Themes added by the instance replace built-in themes with the same ID.
+fn load_themes(extra: Option<&[u8]>) -> Result<ThemeConfig, String> {
+    let mut config: ThemeConfig =
+        serde_json::from_str(include_str!("../static/style/theme/themes.json"))
+            .map_err(|err| err.to_string())?;
+    if let Some(json) = extra {
+        config.merge(serde_json::from_slice(json).map_err(|err| err.to_string())?);
+    }
+    config.check()?;
+    Ok(config)
+}
+
+/// Checks the themes an instance adds, so mistakes are reported on startup instead of ignored.
+pub fn check_themes(json: &[u8]) -> Result<(), String> {
+    load_themes(Some(json)).map(|_| ())
+}
+
 #[derive(Deserialize)]
 struct ThemeConfig {
     themes: Vec<Theme>,
+    #[serde(default)]
+    pairs: Vec<ThemePair>,
+}
+
+impl ThemeConfig {
+    fn merge(&mut self, other: ThemeConfig) {
+        for theme in other.themes {
+            match self.themes.iter_mut().find(|t| t.id == theme.id) {
+                Some(existing) => *existing = theme,
+                None => self.themes.push(theme),
+            }
+        }
+        for pair in other.pairs {
+            match self.pairs.iter_mut().find(|p| p.id == pair.id) {
+                Some(existing) => *existing = pair,
+                None => self.pairs.push(pair),
+            }
+        }
+    }
+
+    /// Checks that the themes of every pair exist.
+    fn check(&self) -> Result<(), String> {
+        for pair in &self.pairs {
+            for theme in [&pair.light, &pair.dark] {
+                if !self.themes.iter().any(|t| &t.id == theme) {
+                    return Err(format!(
+                        "Theme pair '{}' uses unknown theme '{theme}'",
+                        pair.id
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A theme's stylesheet, and the media query it applies to.
+pub struct ThemeStylesheet {
+    pub theme: String,
+    pub media: Option<&'static str>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_keep_other_settings() {
+        let parsed: Settings = serde_json::from_str(r#"{"theme":"catppuccin-latte"}"#).unwrap();
+        assert_eq!(parsed.theme, "catppuccin-latte");
+        assert!(parsed.is_valid());
+
+        let parsed: Settings =
+            serde_json::from_str(r#"{"font_size":"large","removed_setting":true}"#).unwrap();
+        assert_eq!(parsed.theme, Settings::default().theme);
+        assert_eq!(parsed.font_size, "large");
+    }
+
+    #[test]
+    fn invalid_fields_only_reset_themselves() {
+        let parsed = Settings::from_json(
+            r#"{"theme":"nonexistent","font_size":"large","embed_media":"yes","youtube_frontend":"javascript:alert(1)","color_performers":true}"#,
+        );
+        assert_eq!(parsed.theme, Settings::default().theme);
+        assert_eq!(parsed.font_size, "large");
+        assert!(!parsed.embed_media);
+        assert_eq!(parsed.youtube_frontend, "");
+        assert!(parsed.color_performers);
+        assert!(parsed.is_valid());
+
+        assert!(Settings::from_json("not json").is_valid());
+        assert!(Settings::from_json("[1, 2]").is_valid());
+    }
+
+    #[test]
+    fn migrates_unversioned_settings() {
+        let parsed = Settings::from_json(r#"{"theme":"catppuccin-latte"}"#);
+        assert_eq!(parsed.version, SETTINGS_VERSION);
+        assert_eq!(parsed.theme, "catppuccin-latte");
+
+        let parsed = Settings::from_json(r#"{"version":999,"theme":"catppuccin-latte"}"#);
+        assert_eq!(parsed.version, SETTINGS_VERSION);
+    }
+
+    #[test]
+    fn encoded_settings_round_trip() {
+        let custom = Settings {
+            theme: "catppuccin-latte".into(),
+            embed_media: true,
+            youtube_frontend: "https://yewtu.be".into(),
+            ..Settings::default()
+        };
+        let encoded = custom.encode();
+        assert!(
+            encoded
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        );
+        let decoded = Settings::decode(&encoded).unwrap();
+        assert_eq!(
+            serde_json::to_value(decoded).unwrap(),
+            serde_json::to_value(&custom).unwrap()
+        );
+
+        // Only changed settings are included.
+        assert!(Settings::default().encode().len() < 20);
+        assert!(Settings::decode("not base64!").is_none());
+        assert_eq!(
+            custom.changes(),
+            [
+                ("embed media".to_owned(), "true".to_owned()),
+                ("theme".to_owned(), "catppuccin-latte".to_owned()),
+                ("youtube frontend".to_owned(), "https://yewtu.be".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn url_settings_are_carried_in_links() {
+        let mut url = Settings {
+            font_size: "large".into(),
+            ..Settings::default()
+        };
+        assert_eq!(url.query('?'), "");
+        url.from_url = true;
+        assert_eq!(url.query('&'), format!("&s={}", url.encode()));
+        // Whether settings came from the URL isn't part of them.
+        assert!(!Settings::decode(&url.encode()).unwrap().from_url);
+    }
+
+    #[actix_web::test]
+    async fn restore_page_uses_own_settings() {
+        use actix_web::{App, http::header, test};
+
+        let app = test::init_service(App::new().service(restore)).await;
+        let restored = Settings {
+            theme: "catppuccin-latte".into(),
+            ..Settings::default()
+        };
+        let req = test::TestRequest::get()
+            .uri(&format!("/settings/restore?code={}", restored.encode()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        let cache_control = res.headers().get(header::CACHE_CONTROL).unwrap();
+        assert!(cache_control.to_str().unwrap().starts_with("private"));
+
+        let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        assert!(body.contains(r#"<a href="/settings">Cancel</a>"#));
+        assert!(!body.contains("?s=") && !body.contains("&s="));
+    }
+
+    #[test]
+    fn url_settings_cant_change_external_sites() {
+        let crafted = Settings {
+            font_size: "large".into(),
+            embed_media: true,
+            youtube_frontend: "https://attacker.example".into(),
+            ..Settings::default()
+        };
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/?s={}", crafted.encode()))
+            .to_http_request();
+        let decoded = url_settings(&req).unwrap();
+        assert_eq!(decoded.font_size, "large");
+        assert!(!decoded.embed_media);
+        assert!(!decoded.has_frontends());
+    }
+
+    #[test]
+    fn custom_theme_colors() {
+        let custom = Settings::from_json(
+            r##"{"theme":"custom","custom_color_scheme":"light","custom_background":"#fff","custom_text":"red;}body{display:none"}"##,
+        );
+        assert_eq!(custom.theme, CUSTOM_THEME);
+        assert_eq!(custom.custom_background, "#fff");
+        // Only hex colors are allowed.
+        assert_eq!(custom.custom_text, Settings::default().custom_text);
+        let css = custom.custom_theme_css().unwrap();
+        assert!(css.starts_with(":root{color-scheme:light;--background:#fff;"));
+        assert!(css.contains("--text-dark:#85817b;"));
+
+        assert!(Settings::default().custom_theme_css().is_none());
+        assert!(is_color("#0d1117aa"));
+        assert!(!is_color("#0d111"));
+        assert!(!is_color("blue"));
+    }
+
+    #[test]
+    fn theme_pairs_follow_system_appearance() {
+        let stylesheets = Settings::default().theme_stylesheets();
+        let themes: Vec<(&str, Option<&str>)> = stylesheets
+            .iter()
+            .map(|s| (s.theme.as_str(), s.media))
+            .collect();
+        assert_eq!(
+            themes,
+            [
+                ("github-light", None),
+                ("github-dark", Some("(prefers-color-scheme: dark)"))
+            ]
+        );
+
+        let dark = Settings::from_json(r#"{"theme":"github-dark"}"#);
+        assert_eq!(dark.theme_stylesheets().len(), 1);
+        // Pairs must refer to existing themes.
+        for pair in &THEME_CONFIG.pairs {
+            for theme in [&pair.light, &pair.dark] {
+                assert!(THEME_CONFIG.themes.iter().any(|t| &t.id == theme));
+            }
+        }
+    }
+
+    #[test]
+    fn instance_themes_extend_built_in_themes() {
+        let mut config = ThemeConfig {
+            themes: vec![Theme {
+                id: "github-dark".into(),
+                name: "Github Dark".into(),
+            }],
+            pairs: Vec::new(),
+        };
+        config.merge(
+            serde_json::from_str(
+                r#"{"themes":[{"id":"github-dark","name":"Dark"},{"id":"acme","name":"Acme"}]}"#,
+            )
+            .unwrap(),
+        );
+        let names: Vec<&str> = config.themes.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["Dark", "Acme"]);
+    }
+
+    #[test]
+    fn instance_themes_are_checked() {
+        assert!(check_themes(br#"{"themes":[{"id":"acme","name":"Acme"}]}"#).is_ok());
+        assert!(check_themes(br#"{"themes":[{"id":"acme"}]}"#).is_err());
+        assert!(check_themes(br#"[{"id":"acme","name":"Acme"}]"#).is_err());
+        assert!(
+            check_themes(
+                br#"{"themes":[],"pairs":[{"id":"acme","name":"Acme","light":"github-light","dark":"github-dakr"}]}"#
+            )
+            .is_err()
+        );
+        assert!(load_themes(None).is_ok());
+    }
+
+    #[test]
+    fn layout_body_classes() {
+        let layout = Settings {
+            font_size: "larger".into(),
+            lyric_alignment: "center".into(),
+            hide_annotation_highlights: true,
+            ..Settings::default()
+        };
+        assert_eq!(
+            layout.body_class(),
+            "font-larger lyrics-center no-annotation-highlights"
+        );
+        assert_eq!(Settings::default().body_class(), "");
+        assert!(
+            !Settings {
+                line_spacing: "huge".into(),
+                ..Settings::default()
+            }
+            .is_valid()
+        );
+    }
 }
@@ -1,20 +1,73 @@
-use actix_web::{HttpResponse, HttpResponseBuilder};
+use actix_web::{
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+    http::{
+        StatusCode,
+        header::{self, HeaderValue},
+    },
+};
 use askama::Template;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use sha2::{Digest, Sha256};
 
-pub fn template(t: impl Template) -> HttpResponse {
-    template_with_res(HttpResponse::Ok(), t)
+use crate::settings::{has_url_settings, settings_from_req};
+
+/// The Content-Security-Policy sent with every response.
+/// `frame-src` must stay last so additional sources can be appended to it.
+pub const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'self'; frame-src https://www.youtube-nocookie.com/embed/";
+
+/// Adds an allowed frame source to a Content-Security-Policy.
+pub fn content_security_policy_with_frame(csp: &str, frame_src: &str) -> String {
+    format!("{csp} {frame_src}")
+}
+
+/// Gets the Content-Security-Policy that allows a single inline `<style>` block by its hash.
+pub fn content_security_policy_with_style(css: &str) -> String {
+    let hash = STANDARD.encode(Sha256::digest(css));
+    format!("style-src 'self' 'sha256-{hash}'; {CONTENT_SECURITY_POLICY}")
 }
 
-pub fn template_with_res(mut res: HttpResponseBuilder, t: impl Template) -> HttpResponse {
-    res.append_header(("Content-Type", "text/html; charset=utf-8"))
+pub fn template(req: &HttpRequest, t: impl Template) -> HttpResponse {
+    template_with_res(req, HttpResponse::Ok(), t)
+}
+
+pub fn template_with_res(
+    req: &HttpRequest,
+    mut res: HttpResponseBuilder,
+    t: impl Template,
+) -> HttpResponse {
+    res.append_header(("Content-Type", "text/html; charset=utf-8"));
+    if let Some(css) = settings_from_req(req).custom_theme_css() {
+        res.insert_header((
+            header::CONTENT_SECURITY_POLICY,
+            content_security_policy_with_style(&css),
+        ));
+    }
+    let mut response = res.body(t.render().unwrap_or_default());
+    let status = response.status();
+    let headers = response.headers_mut();
+    if status != StatusCode::OK {
+        // Errors may be temporary, and their pages include the request's ID.
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    } else if has_url_settings(req) {
+        // Settings in the URL take precedence over the cookie, so the page only depends on its URL
+        // and can be cached by anyone.
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=604800"),
+        );
+    } else {
         // Caching Setup
         // Since Cloudflare ignores Vary headers, we can't publicly cache all pages since only
         // the last-cached theme would be shown to users. Instead, we privately cache all pages in the
         // browser, which does handle the Vary header correctly. If we didn't have the Vary header,
         // when a user changes themes, it won't be applied to previously visited pages (e.g. the
         // homepage) until the browser requests the page from the server again.
-        .append_header(("Vary", "Cookie"))
-        .append_header(("Cache-Control", "private, max-age=604800"));
-
-    res.body(t.render().unwrap_or_default())
+        headers.insert(header::VARY, HeaderValue::from_static("Cookie"));
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("private, max-age=604800"),
+        );
+    }
+    response
 }
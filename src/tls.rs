@@ -0,0 +1,250 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    App, HttpRequest, HttpResponse, HttpServer, Responder, dev::Server, get, http::header, web,
+};
+use log::{error, info, warn};
+use rustls::{
+    Certificate, PrivateKey, ServerConfig,
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+};
+use rustls_pemfile::Item;
+
+/// How often to check whether the certificate or key changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Creates the TLS config, reloading the certificate whenever it or its key change.
+pub fn server_config(cert_path: &str, key_path: &str) -> Result<ServerConfig, String> {
+    let resolver = Arc::new(CertResolver {
+        cert_path: PathBuf::from(cert_path),
+        key_path: PathBuf::from(key_path),
+        key: RwLock::new(Arc::new(load(cert_path, key_path)?)),
+    });
+    let watched = resolver.clone();
+    thread::spawn(move || watched.watch());
+
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver))
+}
+
+/// Loads a certificate chain and its private key.
+/// Keys can be PKCS#8, PKCS#1 (RSA) or SEC1 (EC) encoded.
+pub fn load(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<CertifiedKey, String> {
+    let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+
+    let certs: Vec<Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(format!(
+            "Failed to find any certs in '{}'",
+            cert_path.display()
+        ));
+    }
+
+    let mut keys = read_pem(key_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        });
+    let key = keys
+        .next()
+        .ok_or_else(|| format!("Failed to find any keys in '{}'", key_path.display()))?;
+    if keys.next().is_some() {
+        warn!(
+            "Found multiple keys in '{}'! Only the first will be used.",
+            key_path.display()
+        );
+    }
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| format!("Unsupported key type in '{}'", key_path.display()))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, String> {
+    File::open(path)
+        .and_then(|file| rustls_pemfile::read_all(&mut BufReader::new(file)))
+        .map_err(|err| format!("Failed to load '{}': {err}", path.display()))
+}
+
+/// Resolves the certificate for every connection, so it can be swapped out while running.
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Reloads the certificate when the files change, e.g. when they're renewed.
+    /// If loading fails, e.g. because only one of them was written yet, it's retried next time.
+    fn watch(&self) {
+        let mut loaded = self.modified();
+        loop {
+            thread::sleep(RELOAD_INTERVAL);
+            let modified = self.modified();
+            if modified == loaded {
+                continue;
+            }
+            match load(&self.cert_path, &self.key_path) {
+                Ok(key) => {
+                    *self.key.write().unwrap() = Arc::new(key);
+                    loaded = modified;
+                    info!("Reloaded TLS certificate '{}'", self.cert_path.display());
+                }
+                Err(err) => error!("Failed to reload TLS certificate: {err}"),
+            }
+        }
+    }
+
+    /// Gets when the files were last modified.
+    /// Symlinks are followed, since that's how certbot links to the latest certificate.
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        [&self.cert_path, &self.key_path]
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+/// Creates a plain HTTP server that redirects to HTTPS, and serves ACME HTTP-01 challenges.
+/// It listens on the same host as the HTTPS listener it redirects to.
+pub fn redirect_server(https_addr: SocketAddr, port: u16) -> io::Result<Server> {
+    let https_port = https_addr.port();
+    info!(
+        "Redirecting HTTP requests on {} to HTTPS",
+        SocketAddr::new(https_addr.ip(), port)
+    );
+
+    Ok(HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(https_port))
+            .service(acme_challenge)
+            .default_service(web::to(redirect))
+    })
+    .workers(1)
+    .bind((https_addr.ip(), port))?
+    .run())
+}
+
+async fn redirect(req: HttpRequest, https_port: web::Data<u16>) -> impl Responder {
+    // The forwarded host can only be used if it was set by a reverse proxy we trust.
+    let host = if crate::args().trust_proxy_headers {
+        req.connection_info().host().to_owned()
+    } else {
+        req.headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| req.uri().host())
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let host = strip_port(&host);
+    if host.is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let location = match **https_port {
+        443 => format!("https://{host}{}", req.uri()),
+        port => format!("https://{host}:{port}{}", req.uri()),
+    };
+    HttpResponse::MovedPermanently()
+        .append_header(("Location", location))
+        .finish()
+}
+
+/// Serves the files certbot's webroot plugin and other ACME clients create to prove domain ownership.
+#[get("/.well-known/acme-challenge/{token}")]
+async fn acme_challenge(token: web::Path<String>) -> impl Responder {
+    let Some(dir) = &crate::args().acme_challenge_dir else {
+        return HttpResponse::NotFound().finish();
+    };
+    // Tokens are base64url, so this also stops them from escaping the directory.
+    if token.is_empty()
+        || !token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return HttpResponse::NotFound().finish();
+    }
+    match fs::read(
+        Path::new(dir)
+            .join(".well-known/acme-challenge")
+            .join(token.as_str()),
+    ) {
+        Ok(contents) => HttpResponse::Ok()
+            .append_header(("Content-Type", "text/plain"))
+            .body(contents),
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Removes the port from a `Host` header, which might be an IPv6 address.
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port))
+            if port.chars().all(|c| c.is_ascii_digit())
+                && (name.ends_with(']') || !name.contains(':')) =>
+        {
+            name
+        }
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_ports_from_hosts() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    #[actix_web::test]
+    async fn redirects_to_host_header() {
+        use actix_web::test;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(8443u16))
+                .default_service(web::to(redirect)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/lyrics?id=1")
+            .insert_header((header::HOST, "example.com:8080"))
+            .insert_header(("X-Forwarded-Host", "attacker.example"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::LOCATION).unwrap(),
+            "https://example.com:8443/lyrics?id=1"
+        );
+    }
+}
@@ -12,6 +12,38 @@ pub fn borrowed_u8_eq(a: &u8, b: &u8) -> bool {
     *a == *b
 }
 
+/// Gets the native name of a language from its ISO 639-1 code, as used by Genius.
+/// Will return the code itself if it isn't known.
+///
+/// `es` -> `Español`
+pub fn language_name(code: &str) -> &str {
+    match code {
+        "ar" => "العربية",
+        "cs" => "Čeština",
+        "de" => "Deutsch",
+        "el" => "Ελληνικά",
+        "en" => "English",
+        "es" => "Español",
+        "fa" => "فارسی",
+        "fr" => "Français",
+        "he" => "עברית",
+        "hi" => "हिन्दी",
+        "it" => "Italiano",
+        "ja" => "日本語",
+        "ko" => "한국어",
+        "nl" => "Nederlands",
+        "pl" => "Polski",
+        "pt" => "Português",
+        "ro" => "Română",
+        "ru" => "Русский",
+        "sv" => "Svenska",
+        "tr" => "Türkçe",
+        "uk" => "Українська",
+        "zh" => "中文",
+        _ => code,
+    }
+}
+
 /// Gets the path part from a full URL.
 /// Will return an empty string if invalid.
 ///
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use lazy_regex::*;
+use regex::Regex;
+
+use crate::genius::GeniusArtist;
+use crate::utils;
+
+/// The amount of distinct performer colors defined in `lyrics.css`.
+const PERFORMER_COLORS: usize = 6;
+
+/// Separates performers, e.g. `A, B & C`. `x` only counts in lowercase with spaces around it,
+/// so names like `Lil Nas X` aren't split.
+static PERFORMER_SEPARATOR_PATTERN: Lazy<Regex> =
+    lazy_regex!(r"\s*(?:,|&|\+|\band\b)\s*|\s+(?-i:x)\s+"i);
+
+/// The kind of section a verse header describes, e.g. `Verse 2` or `Chorus`.
+#[derive(Debug, PartialEq)]
+pub enum SectionKind {
+    Intro,
+    Verse(Option<u32>),
+    PreChorus,
+    Chorus,
+    PostChorus,
+    Hook,
+    Bridge,
+    Interlude,
+    Outro,
+    Custom,
+}
+
+impl SectionKind {
+    fn parse(label: &str) -> Self {
+        let label = label.trim().to_lowercase();
+        let (name, number) = match label.rsplit_once(' ') {
+            Some((name, number)) if number.parse::<u32>().is_ok() => (name, number.parse().ok()),
+            _ => (label.as_str(), None),
+        };
+        match name {
+            "intro" => Self::Intro,
+            "verse" => Self::Verse(number),
+            "pre-chorus" | "prechorus" | "pre chorus" => Self::PreChorus,
+            "chorus" => Self::Chorus,
+            "post-chorus" | "postchorus" | "post chorus" => Self::PostChorus,
+            "hook" => Self::Hook,
+            "bridge" => Self::Bridge,
+            "interlude" => Self::Interlude,
+            "outro" => Self::Outro,
+            _ => Self::Custom,
+        }
+    }
+
+    /// The name used in the section's CSS class.
+    pub fn css_name(&self) -> &'static str {
+        match self {
+            Self::Intro => "intro",
+            Self::Verse(_) => "verse",
+            Self::PreChorus => "pre-chorus",
+            Self::Chorus => "chorus",
+            Self::PostChorus => "post-chorus",
+            Self::Hook => "hook",
+            Self::Bridge => "bridge",
+            Self::Interlude => "interlude",
+            Self::Outro => "outro",
+            Self::Custom => "custom",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Performer {
+    pub name: String,
+    /// The path to the performer's artist page, if they're credited on the song.
+    pub path: Option<String>,
+    /// The text between this performer and the next one, e.g. ` & `.
+    pub separator: String,
+}
+
+/// A parsed verse header, e.g. `[Verse 2: Artist & Other]`.
+#[derive(Debug)]
+pub struct VerseHeader {
+    /// The section label as written, e.g. `Verse 2`.
+    pub label: String,
+    pub kind: SectionKind,
+    pub performers: Vec<Performer>,
+    /// The color index of the verse's performer, if color-coding is enabled.
+    pub color: Option<usize>,
+}
+
+impl VerseHeader {
+    /// Parses a raw verse title.
+    /// Will return `None` if the title isn't surrounded by brackets.
+    ///
+    /// `[Verse 2: Artist & Other]` -> `Verse 2` performed by `Artist` and `Other`
+    pub fn parse(title: &str, credits: &[&GeniusArtist]) -> Option<Self> {
+        let inner = title.strip_prefix('[')?.strip_suffix(']')?;
+        let (label, performers) = match inner.split_once(':') {
+            Some((label, performers)) => (label.trim(), parse_performers(performers, credits)),
+            None => (inner.trim(), Vec::new()),
+        };
+        Some(VerseHeader {
+            label: label.to_owned(),
+            kind: SectionKind::parse(label),
+            performers,
+            color: None,
+        })
+    }
+}
+
+fn parse_performers(text: &str, credits: &[&GeniusArtist]) -> Vec<Performer> {
+    let text = text.trim();
+
+    // (start, end) of each name, and the start of the next one.
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    for separator in PERFORMER_SEPARATOR_PATTERN.find_iter(text) {
+        tokens.push((start, separator.start(), separator.end()));
+        start = separator.end();
+    }
+    tokens.push((start, text.len(), text.len()));
+
+    let mut performers = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        // Greedily join tokens that make up a credited name, e.g. `Simon & Garfunkel`.
+        let (end, artist) = (i..tokens.len())
+            .rev()
+            .find_map(|j| {
+                find_credit(&text[tokens[i].0..tokens[j].1], credits).map(|a| (j, Some(a)))
+            })
+            .unwrap_or((i, None));
+
+        let name = &text[tokens[i].0..tokens[end].1];
+        if !name.is_empty() {
+            performers.push(Performer {
+                name: name.to_owned(),
+                path: artist.map(|a| utils::path_from_url(&a.url)),
+                separator: text[tokens[end].1..tokens[end].2].to_owned(),
+            });
+        }
+        i = end + 1;
+    }
+
+    performers
+}
+
+fn find_credit<'a>(name: &str, credits: &[&'a GeniusArtist]) -> Option<&'a GeniusArtist> {
+    credits
+        .iter()
+        .find(|artist| artist.name.trim().eq_ignore_ascii_case(name.trim()))
+        .copied()
+}
+
+/// Assigns a color to each verse performed by a single performer.
+/// Colors are only assigned if more than one performer is present.
+pub fn assign_colors<'a>(headers: impl Iterator<Item = &'a mut VerseHeader>) {
+    let headers: Vec<&mut VerseHeader> = headers.collect();
+
+    let mut indices = HashMap::new();
+    for performer in headers.iter().flat_map(|h| h.performers.iter()) {
+        let len = indices.len();
+        indices.entry(performer.name.to_lowercase()).or_insert(len);
+    }
+    if indices.len() < 2 {
+        return;
+    }
+
+    for header in headers {
+        if let [performer] = header.performers.as_slice() {
+            header.color = indices
+                .get(&performer.name.to_lowercase())
+                .map(|i| i % PERFORMER_COLORS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artist(name: &str) -> GeniusArtist {
+        GeniusArtist {
+            id: 0,
+            name: name.to_owned(),
+            alternate_names: None,
+            image_url: String::new(),
+            url: format!("https://genius.com/artists/{}", name.replace(' ', "-")),
+            description: None,
+            popular_songs: None,
+            facebook_name: None,
+            instagram_name: None,
+            twitter_name: None,
+        }
+    }
+
+    #[test]
+    fn parse_section_kinds() {
+        let parse = |title| VerseHeader::parse(title, &[]).unwrap().kind;
+        assert_eq!(parse("[Intro]"), SectionKind::Intro);
+        assert_eq!(parse("[Verse 2]"), SectionKind::Verse(Some(2)));
+        assert_eq!(parse("[Verse]"), SectionKind::Verse(None));
+        assert_eq!(parse("[Pre-Chorus: Someone]"), SectionKind::PreChorus);
+        assert_eq!(parse("[Skit]"), SectionKind::Custom);
+        assert!(VerseHeader::parse("Not a header", &[]).is_none());
+    }
+
+    #[test]
+    fn parse_performers_with_credits() {
+        let simon = artist("Simon & Garfunkel");
+        let other = artist("Other");
+        let header = VerseHeader::parse(
+            "[Chorus: Simon & Garfunkel, Other & Someone]",
+            &[&simon, &other],
+        )
+        .unwrap();
+
+        let names: Vec<_> = header.performers.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Simon & Garfunkel", "Other", "Someone"]);
+        assert_eq!(
+            header.performers[0].path.as_deref(),
+            Some("artists/Simon-&-Garfunkel")
+        );
+        assert_eq!(header.performers[0].separator, ", ");
+        assert_eq!(header.performers[1].separator, " & ");
+        assert_eq!(header.performers[2].path, None);
+    }
+
+    #[test]
+    fn only_split_on_spaced_lowercase_x() {
+        let names = |title| {
+            VerseHeader::parse(title, &[])
+                .unwrap()
+                .performers
+                .into_iter()
+                .map(|p| p.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("[Verse 1: Lil Nas X]"), ["Lil Nas X"]);
+        assert_eq!(
+            names("[Verse 1: Lil Nas X & Other]"),
+            ["Lil Nas X", "Other"]
+        );
+        assert_eq!(names("[Chorus: A x B]"), ["A", "B"]);
+        assert_eq!(names("[Chorus: Xzibit]"), ["Xzibit"]);
+    }
+
+    #[test]
+    fn assign_colors_only_when_collaborative() {
+        let mut solo = [
+            VerseHeader::parse("[Verse 1: A]", &[]).unwrap(),
+            VerseHeader::parse("[Verse 2: A]", &[]).unwrap(),
+        ];
+        assign_colors(solo.iter_mut());
+        assert!(solo.iter().all(|h| h.color.is_none()));
+
+        let mut collab = [
+            VerseHeader::parse("[Verse 1: A]", &[]).unwrap(),
+            VerseHeader::parse("[Verse 2: B]", &[]).unwrap(),
+            VerseHeader::parse("[Chorus: A & B]", &[]).unwrap(),
+        ];
+        assign_colors(collab.iter_mut());
+        let colors: Vec<_> = collab.iter().map(|h| h.color).collect();
+        assert_eq!(colors, [Some(0), Some(1), None]);
+    }
+}